@@ -0,0 +1,205 @@
+//! Single AES rounds, equivalent to the x86 `aesenc` and `aesdec`
+//! instructions. RandomX never uses the full AES-128: each round key is used
+//! as is, there is no key expansion. See
+//! [3](https://github.com/tevador/RandomX/blob/master/doc/specs.md#3-aes).
+//!
+//! Two backends are available: the AES-NI instructions, detected at runtime,
+//! and a portable table-based implementation. Both give the same results.
+use crate::flags::RandomXFlags;
+use crate::Error;
+
+/// The implementation of the AES rounds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AesBackend {
+    /// Portable table-based implementation
+    #[default]
+    Software,
+    /// AES-NI instructions. Only available on x86-64 CPUs supporting them,
+    /// the backend is obtained from [AesBackend::from_flags].
+    Hardware(HardwareAes),
+}
+
+/// Proof that the CPU supports the AES instructions. It can only be built
+/// by [AesBackend::from_flags], after the check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HardwareAes(());
+
+impl AesBackend {
+    /// Return true if the CPU supports the AES instructions
+    pub fn hardware_available() -> bool {
+        #[cfg(target_arch = "x86_64")]
+        {
+            std::arch::is_x86_feature_detected!("aes")
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            false
+        }
+    }
+
+    /// Select the backend requested by `flags`: the AES-NI instructions with
+    /// [RandomXFlags::HARD_AES], the software implementation otherwise.
+    pub fn from_flags(flags: RandomXFlags) -> Result<Self, Error> {
+        if !flags.contains(RandomXFlags::HARD_AES) {
+            Ok(AesBackend::Software)
+        } else if Self::hardware_available() {
+            Ok(AesBackend::Hardware(HardwareAes(())))
+        } else {
+            Err(Error::UnsupportedFlag(RandomXFlags::HARD_AES))
+        }
+    }
+
+    /// One encryption round: SubBytes, ShiftRows, MixColumns and AddRoundKey
+    #[inline]
+    pub fn enc_round(self, state: &mut [u8; 16], key: &[u8; 16]) {
+        match self {
+            AesBackend::Software => soft_enc_round(state, key),
+            #[cfg(target_arch = "x86_64")]
+            // SAFETY: HardwareAes is only built after checking that the CPU
+            // supports the AES instructions.
+            AesBackend::Hardware(_) => unsafe { hard::enc_round(state, key) },
+            #[cfg(not(target_arch = "x86_64"))]
+            AesBackend::Hardware(_) => unreachable!(),
+        }
+    }
+
+    /// One decryption round: InvShiftRows, InvSubBytes, InvMixColumns and
+    /// AddRoundKey
+    #[inline]
+    pub fn dec_round(self, state: &mut [u8; 16], key: &[u8; 16]) {
+        match self {
+            AesBackend::Software => soft_dec_round(state, key),
+            #[cfg(target_arch = "x86_64")]
+            // SAFETY: see enc_round
+            AesBackend::Hardware(_) => unsafe { hard::dec_round(state, key) },
+            #[cfg(not(target_arch = "x86_64"))]
+            AesBackend::Hardware(_) => unreachable!(),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod hard {
+    use std::arch::x86_64::{
+        __m128i, _mm_aesdec_si128, _mm_aesenc_si128, _mm_loadu_si128, _mm_storeu_si128,
+    };
+
+    #[target_feature(enable = "aes")]
+    pub unsafe fn enc_round(state: &mut [u8; 16], key: &[u8; 16]) {
+        let s = _mm_loadu_si128(state.as_ptr() as *const __m128i);
+        let k = _mm_loadu_si128(key.as_ptr() as *const __m128i);
+        _mm_storeu_si128(state.as_mut_ptr() as *mut __m128i, _mm_aesenc_si128(s, k));
+    }
+
+    #[target_feature(enable = "aes")]
+    pub unsafe fn dec_round(state: &mut [u8; 16], key: &[u8; 16]) {
+        let s = _mm_loadu_si128(state.as_ptr() as *const __m128i);
+        let k = _mm_loadu_si128(key.as_ptr() as *const __m128i);
+        _mm_storeu_si128(state.as_mut_ptr() as *mut __m128i, _mm_aesdec_si128(s, k));
+    }
+}
+
+/// Multiplication in GF(2^8) with the AES polynomial x^8 + x^4 + x^3 + x + 1
+const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut res = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            res ^= a;
+        }
+        let carry = a & 0x80;
+        a <<= 1;
+        if carry != 0 {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    res
+}
+
+const fn build_sbox() -> [u8; 256] {
+    let mut sbox = [0u8; 256];
+    let mut x = 0;
+    while x < 256 {
+        // Multiplicative inverse, x^254, with 0 mapped to 0
+        let mut inv: u8 = 1;
+        let mut i = 0;
+        while i < 254 {
+            inv = gf_mul(inv, x as u8);
+            i += 1;
+        }
+        if x == 0 {
+            inv = 0;
+        }
+        // Affine transformation
+        sbox[x] = inv
+            ^ inv.rotate_left(1)
+            ^ inv.rotate_left(2)
+            ^ inv.rotate_left(3)
+            ^ inv.rotate_left(4)
+            ^ 0x63;
+        x += 1;
+    }
+    sbox
+}
+
+const fn build_inv_sbox(sbox: &[u8; 256]) -> [u8; 256] {
+    let mut inv_sbox = [0u8; 256];
+    let mut x = 0;
+    while x < 256 {
+        inv_sbox[sbox[x] as usize] = x as u8;
+        x += 1;
+    }
+    inv_sbox
+}
+
+/// Build the table giving the contribution of a byte of the first row to a
+/// column, for the coefficients `c` of the first column of the (inverse)
+/// MixColumns matrix. The tables of the other rows are rotations of it.
+const fn build_table(sbox: &[u8; 256], c: [u8; 4]) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut x = 0;
+    while x < 256 {
+        let s = sbox[x];
+        table[x] = u32::from_le_bytes([
+            gf_mul(s, c[0]),
+            gf_mul(s, c[1]),
+            gf_mul(s, c[2]),
+            gf_mul(s, c[3]),
+        ]);
+        x += 1;
+    }
+    table
+}
+
+const SBOX: [u8; 256] = build_sbox();
+const INV_SBOX: [u8; 256] = build_inv_sbox(&SBOX);
+const TE0: [u32; 256] = build_table(&SBOX, [2, 1, 1, 3]);
+const TD0: [u32; 256] = build_table(&INV_SBOX, [14, 9, 13, 11]);
+
+/// Portable `aesenc`. The state is stored column by column: byte `4 * c + r`
+/// is at row `r` of column `c`. ShiftRows moves row `r` `r` columns to the
+/// left.
+fn soft_enc_round(state: &mut [u8; 16], key: &[u8; 16]) {
+    let s = *state;
+    for c in 0..4 {
+        let column = TE0[s[4 * c] as usize]
+            ^ TE0[s[4 * ((c + 1) % 4) + 1] as usize].rotate_left(8)
+            ^ TE0[s[4 * ((c + 2) % 4) + 2] as usize].rotate_left(16)
+            ^ TE0[s[4 * ((c + 3) % 4) + 3] as usize].rotate_left(24);
+        let k = u32::from_le_bytes(key[4 * c..4 * c + 4].try_into().unwrap());
+        state[4 * c..4 * c + 4].copy_from_slice(&(column ^ k).to_le_bytes());
+    }
+}
+
+/// Portable `aesdec`. InvShiftRows moves row `r` `r` columns to the right.
+fn soft_dec_round(state: &mut [u8; 16], key: &[u8; 16]) {
+    let s = *state;
+    for c in 0..4 {
+        let column = TD0[s[4 * c] as usize]
+            ^ TD0[s[4 * ((c + 3) % 4) + 1] as usize].rotate_left(8)
+            ^ TD0[s[4 * ((c + 2) % 4) + 2] as usize].rotate_left(16)
+            ^ TD0[s[4 * ((c + 1) % 4) + 3] as usize].rotate_left(24);
+        let k = u32::from_le_bytes(key[4 * c..4 * c + 4].try_into().unwrap());
+        state[4 * c..4 * c + 4].copy_from_slice(&(column ^ k).to_le_bytes());
+    }
+}
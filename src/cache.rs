@@ -0,0 +1,145 @@
+//! The RandomX Cache, described in [section
+//! 7](https://github.com/tevador/RandomX/blob/master/doc/specs.md#7-dataset).
+use argon2::{Algorithm, Argon2, Block, Params, Version};
+
+use crate::flags::RandomXFlags;
+use crate::helpers::reciprocal;
+#[cfg(feature = "jit")]
+use crate::jit::SuperscalarHashJit;
+use crate::memory::{Memory, PageSize};
+use crate::parameters::{
+    RANDOMX_ARGON_ITERATIONS, RANDOMX_ARGON_LANES, RANDOMX_ARGON_MEMORY, RANDOMX_ARGON_SALT,
+    RANDOMX_CACHE_ACCESSES, RANDOMX_CACHE_LINE_SIZE, RANDOMX_CACHE_SIZE, SUPERSCALAR_ADD1,
+    SUPERSCALAR_ADD2, SUPERSCALAR_ADD3, SUPERSCALAR_ADD4, SUPERSCALAR_ADD5, SUPERSCALAR_ADD6,
+    SUPERSCALAR_ADD7, SUPERSCALAR_MUL0,
+};
+use crate::superscalar::{execute_superscalar, SuperscalarInstructionType, SuperscalarProgram};
+use crate::vm::{imm32, opcode};
+use crate::{BlakeGenerator, Error};
+
+/// Number of 64-bit words in an Argon2 block
+const BLOCK_WORDS: usize = 128;
+
+/// Number of 64-bit words in a cache line
+const CACHE_LINE_WORDS: usize = RANDOMX_CACHE_LINE_SIZE as usize / 8;
+
+/// The 256 MiB Cache, from which the Dataset items are computed. In light
+/// mode, it is used directly by the VM.
+pub struct Cache {
+    /// The Argon2d memory, filled from the key
+    pub memory: Memory<Block>,
+    /// The SuperscalarHash programs used to compute the Dataset items
+    pub programs: Vec<SuperscalarProgram>,
+    /// The reciprocals used by the `IMUL_RCP` instructions of the programs.
+    /// As in the reference implementation, the immediate of these
+    /// instructions is replaced by an index into this vector.
+    pub reciprocal_cache: Vec<u64>,
+    /// The programs compiled to machine code, used to initialize the
+    /// Dataset. `None` if the executable memory could not be allocated.
+    #[cfg(feature = "jit")]
+    pub jit: Option<SuperscalarHashJit>,
+}
+
+impl Cache {
+    /// Initialize the Cache from the key, as described in
+    /// [7.1](https://github.com/tevador/RandomX/blob/master/doc/specs.md#71-cache-construction),
+    /// and generate the `RANDOMX_CACHE_ACCESSES` SuperscalarHash programs.
+    /// With the `jit` feature, the programs are also compiled.
+    ///
+    /// Like `randomx_init_cache` in the reference implementation, the cache
+    /// contains the raw Argon2d memory blocks after the last pass: the final
+    /// Argon2 hash is not computed.
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        Self::with_flags(key, RandomXFlags::DEFAULT)
+    }
+
+    /// [Cache::new], allocating the memory with huge pages if
+    /// [RandomXFlags::LARGE_PAGES] is set and they are available. The other
+    /// flags are ignored.
+    pub fn with_flags(key: &[u8], flags: RandomXFlags) -> Result<Self, Error> {
+        let params = Params::new(
+            RANDOMX_ARGON_MEMORY as u32,
+            RANDOMX_ARGON_ITERATIONS as u32,
+            RANDOMX_ARGON_LANES as u32,
+            None,
+        )?;
+        let argon2 = Argon2::new(Algorithm::Argon2d, Version::V0x13, params);
+        let mut memory = Memory::new(RANDOMX_ARGON_MEMORY as usize, PageSize::from_flags(flags))?;
+        // The reference implementation uses the raw memory blocks, the final
+        // Argon2 hash is never computed.
+        argon2.fill_memory(key, &RANDOMX_ARGON_SALT, &mut memory[..])?;
+
+        let mut gen = BlakeGenerator::from_seed(key.to_vec(), 0);
+        let mut programs = Vec::with_capacity(RANDOMX_CACHE_ACCESSES as usize);
+        let mut reciprocal_cache = Vec::new();
+        for _ in 0..RANDOMX_CACHE_ACCESSES {
+            let mut program = SuperscalarProgram::generate(&mut gen);
+            for instr in program
+                .program_buffer
+                .iter_mut()
+                .take(program.size as usize)
+            {
+                if SuperscalarInstructionType::from_opcode(opcode(*instr))
+                    == SuperscalarInstructionType::IMUL_RCP
+                {
+                    let rcp = reciprocal(imm32(*instr) as u64);
+                    *instr = (*instr & 0xFFFFFFFF) | ((reciprocal_cache.len() as u64) << 32);
+                    reciprocal_cache.push(rcp);
+                }
+            }
+            programs.push(program);
+        }
+
+        Ok(Cache {
+            memory,
+            #[cfg(feature = "jit")]
+            jit: SuperscalarHashJit::new(&programs, &reciprocal_cache).ok(),
+            programs,
+            reciprocal_cache,
+        })
+    }
+
+    /// Return the 64-bit word at `index` of the cache memory.
+    pub fn word(&self, index: usize) -> u64 {
+        self.memory[index / BLOCK_WORDS].as_ref()[index % BLOCK_WORDS]
+    }
+
+    /// Compute the Dataset item `item_number`, as described in
+    /// [7.3](https://github.com/tevador/RandomX/blob/master/doc/specs.md#73-dataset-block-generation).
+    pub fn init_dataset_item(&self, item_number: u64) -> [u64; 8] {
+        let mut r = [0u64; 8];
+        r[0] = item_number.wrapping_add(1).wrapping_mul(SUPERSCALAR_MUL0);
+        r[1] = r[0] ^ SUPERSCALAR_ADD1;
+        r[2] = r[0] ^ SUPERSCALAR_ADD2;
+        r[3] = r[0] ^ SUPERSCALAR_ADD3;
+        r[4] = r[0] ^ SUPERSCALAR_ADD4;
+        r[5] = r[0] ^ SUPERSCALAR_ADD5;
+        r[6] = r[0] ^ SUPERSCALAR_ADD6;
+        r[7] = r[0] ^ SUPERSCALAR_ADD7;
+
+        let mask = RANDOMX_CACHE_SIZE / RANDOMX_CACHE_LINE_SIZE - 1;
+        let mut register_value = item_number;
+        for program in self.programs.iter() {
+            let line = ((register_value & mask) as usize) * CACHE_LINE_WORDS;
+            execute_superscalar(&mut r, program, Some(&self.reciprocal_cache));
+            for (q, reg) in r.iter_mut().enumerate() {
+                *reg ^= self.word(line + q);
+            }
+            register_value = r[program.addr_reg as usize];
+        }
+        r
+    }
+
+    /// Compute the Dataset item `item_number` and return it as the 64 bytes
+    /// stored in the Dataset, the registers `r0`-`r7` in little-endian order.
+    pub fn dataset_item(&self, item_number: u64) -> [u8; 64] {
+        let mut item = [0u8; 64];
+        for (bytes, reg) in item
+            .chunks_exact_mut(8)
+            .zip(self.init_dataset_item(item_number))
+        {
+            bytes.copy_from_slice(&reg.to_le_bytes());
+        }
+        item
+    }
+}
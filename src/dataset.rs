@@ -0,0 +1,183 @@
+//! The RandomX Dataset, described in [section
+//! 7](https://github.com/tevador/RandomX/blob/master/doc/specs.md#7-dataset).
+//! It is only needed in fast mode: in light mode, the items are computed from
+//! the Cache when they are read.
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use crate::cache::Cache;
+use crate::flags::RandomXFlags;
+use crate::memory::{Memory, PageSize};
+use crate::parameters::{RANDOMX_DATASET_INDEX_SIZE, RANDOMX_DATASET_ITEM_COUNT};
+use crate::Error;
+
+/// Size in bytes of a Dataset item
+const ITEM_SIZE: usize = RANDOMX_DATASET_INDEX_SIZE as usize;
+
+/// The 2080 MiB Dataset, made of `RANDOMX_DATASET_ITEM_COUNT` items of 64
+/// bytes.
+pub struct Dataset {
+    /// The items, stored contiguously
+    pub memory: Memory<u8>,
+    /// Set once items have been computed from a Cache
+    initialized: AtomicBool,
+}
+
+impl Dataset {
+    /// Allocate a zeroed Dataset. It must be initialized from a Cache before
+    /// being used.
+    pub fn new() -> Result<Self, Error> {
+        Self::with_page_size(PageSize::Normal)
+    }
+
+    /// [Dataset::new], allocating the memory with huge pages if
+    /// [RandomXFlags::LARGE_PAGES] is set and they are available. The other
+    /// flags are ignored.
+    pub fn with_flags(flags: RandomXFlags) -> Result<Self, Error> {
+        Self::with_page_size(PageSize::from_flags(flags))
+    }
+
+    /// [Dataset::new], allocating the memory with the largest pages
+    /// available up to `max_page_size`. This is the only way to request
+    /// 1 GiB huge pages.
+    pub fn with_page_size(max_page_size: PageSize) -> Result<Self, Error> {
+        // The pages are only committed when the items are written
+        let memory = Memory::new(
+            RANDOMX_DATASET_ITEM_COUNT as usize * ITEM_SIZE,
+            max_page_size,
+        )?;
+        Ok(Dataset {
+            memory,
+            initialized: AtomicBool::new(false),
+        })
+    }
+
+    /// Compute the `count` items starting at `start_item` from the Cache, like
+    /// `randomx_init_dataset` in the reference implementation.
+    pub fn init(&mut self, cache: &Cache, start_item: u64, count: u64) -> Result<(), Error> {
+        match start_item.checked_add(count) {
+            Some(end_item) if end_item <= RANDOMX_DATASET_ITEM_COUNT => {
+                let start = start_item as usize * ITEM_SIZE;
+                let end = end_item as usize * ITEM_SIZE;
+                init_items(cache, start_item, &mut self.memory[start..end]);
+                self.set_initialized();
+                Ok(())
+            }
+            _ => Err(Error::InvalidItemRange { start_item, count }),
+        }
+    }
+
+    /// Compute all the items from the Cache, splitting the work evenly
+    /// between `threads` threads.
+    pub fn init_parallel(&mut self, cache: &Cache, threads: NonZeroUsize) {
+        let items_per_thread = (RANDOMX_DATASET_ITEM_COUNT as usize).div_ceil(threads.get());
+        thread::scope(|s| {
+            for mut chunk in self.chunks_mut(items_per_thread) {
+                s.spawn(move || chunk.init(cache));
+            }
+        });
+    }
+
+    /// Split the Dataset into disjoint chunks of `items_per_chunk` items, the
+    /// last one being shorter if needed. The chunks can be initialized from
+    /// different threads, for example from a custom thread pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `items_per_chunk` is 0.
+    pub fn chunks_mut(&mut self, items_per_chunk: usize) -> impl Iterator<Item = DatasetChunk<'_>> {
+        let initialized = &self.initialized;
+        self.memory
+            .chunks_mut(items_per_chunk * ITEM_SIZE)
+            .enumerate()
+            .map(move |(i, memory)| DatasetChunk {
+                start_item: (i * items_per_chunk) as u64,
+                memory,
+                initialized,
+            })
+    }
+
+    /// Compute all the items from the Cache, using one thread per available
+    /// core.
+    pub fn init_all(&mut self, cache: &Cache) {
+        let threads = thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+        self.init_parallel(cache, threads);
+    }
+
+    /// Return true once items have been computed from a Cache, with
+    /// [Dataset::init], [Dataset::init_all], [Dataset::init_parallel] or
+    /// [DatasetChunk::init], or once [Dataset::set_initialized] has been
+    /// called. A [Vm](crate::vm::Vm) in fast mode requires an initialized
+    /// Dataset.
+    pub fn is_initialized(&self) -> bool {
+        self.initialized.load(Ordering::Relaxed)
+    }
+
+    /// Mark the Dataset as initialized, for a Dataset whose memory was
+    /// filled directly, for example with items saved in a file
+    pub fn set_initialized(&mut self) {
+        *self.initialized.get_mut() = true;
+    }
+
+    /// Return the 64 bytes of the item `item_number`. Fails with
+    /// [Error::InvalidItemRange] if there is no such item.
+    pub fn item(&self, item_number: u64) -> Result<&[u8], Error> {
+        if item_number < RANDOMX_DATASET_ITEM_COUNT {
+            Ok(self.item_in_range(item_number))
+        } else {
+            Err(Error::InvalidItemRange {
+                start_item: item_number,
+                count: 1,
+            })
+        }
+    }
+
+    /// [Dataset::item], for the item numbers computed by the VM, which are
+    /// always in range
+    pub(crate) fn item_in_range(&self, item_number: u64) -> &[u8] {
+        let start = item_number as usize * ITEM_SIZE;
+        &self.memory[start..start + ITEM_SIZE]
+    }
+}
+
+/// Consecutive items of a [Dataset], returned by [Dataset::chunks_mut]
+pub struct DatasetChunk<'a> {
+    /// Number of the first item of the chunk
+    pub start_item: u64,
+    /// The items of the chunk, stored contiguously
+    pub memory: &'a mut [u8],
+    /// The initialization flag of the Dataset
+    initialized: &'a AtomicBool,
+}
+
+impl DatasetChunk<'_> {
+    /// Return the number of items of the chunk
+    pub fn item_count(&self) -> u64 {
+        (self.memory.len() / ITEM_SIZE) as u64
+    }
+
+    /// Compute the items of the chunk from the Cache. The Dataset is marked
+    /// as initialized.
+    pub fn init(&mut self, cache: &Cache) {
+        init_items(cache, self.start_item, self.memory);
+        self.initialized.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Compute the consecutive items starting at `start_item` into `items`, whose
+/// length must be a multiple of 64. It can be used to initialize disjoint
+/// parts of the Dataset memory, obtained with [Dataset::chunks_mut], from a
+/// custom thread pool.
+///
+/// The compiled programs of the Cache are used when available.
+pub fn init_items(cache: &Cache, start_item: u64, items: &mut [u8]) {
+    #[cfg(feature = "jit")]
+    if let Some(jit) = &cache.jit {
+        jit.init_items(&cache.memory, start_item, items);
+        return;
+    }
+    for (i, item) in items.chunks_exact_mut(ITEM_SIZE).enumerate() {
+        item.copy_from_slice(&cache.dataset_item(start_item + i as u64));
+    }
+}
@@ -0,0 +1,65 @@
+//! Errors returned by the fallible functions of the crate
+use std::fmt;
+
+use crate::flags::RandomXFlags;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The length of an AesHash1R input is not a multiple of 64 bytes
+    MisalignedInput { length: usize },
+    /// Memory of `size` bytes could not be allocated
+    AllocationFailed { size: usize },
+    /// The protection of the JIT memory could not be changed
+    ProtectionFailed,
+    /// The flags are not supported by this build or by the CPU
+    UnsupportedFlag(RandomXFlags),
+    /// A Cache is required by the virtual machine in light mode
+    CacheNotInitialized,
+    /// An initialized Dataset is required by the virtual machine in fast
+    /// mode
+    DatasetNotInitialized,
+    /// No pipeline of hashes was started with `Vm::calculate_hash_first`
+    NoHashInProgress,
+    /// The items are out of the Dataset
+    InvalidItemRange { start_item: u64, count: u64 },
+    /// Argon2 failed while filling the Cache
+    Argon2(argon2::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MisalignedInput { length } => write!(
+                f,
+                "input length is not a multiple of 64 bytes: {} bytes",
+                length
+            ),
+            Error::AllocationFailed { size } => write!(f, "failed to allocate {} bytes", size),
+            Error::ProtectionFailed => {
+                write!(f, "failed to change the protection of the JIT memory")
+            }
+            Error::UnsupportedFlag(flags) => write!(f, "unsupported flags: {:#x}", flags.bits()),
+            Error::CacheNotInitialized => write!(f, "a Cache is required in light mode"),
+            Error::DatasetNotInitialized => {
+                write!(f, "an initialized Dataset is required in fast mode")
+            }
+            Error::NoHashInProgress => {
+                write!(f, "no hash in progress, see Vm::calculate_hash_first")
+            }
+            Error::InvalidItemRange { start_item, count } => write!(
+                f,
+                "invalid Dataset item range: {} items starting at {}",
+                count, start_item
+            ),
+            Error::Argon2(err) => write!(f, "Argon2 error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<argon2::Error> for Error {
+    fn from(err: argon2::Error) -> Self {
+        Error::Argon2(err)
+    }
+}
@@ -0,0 +1,93 @@
+//! Configuration of a RandomX virtual machine, mirroring the `randomx_flags`
+//! of the reference implementation.
+use std::ops::{BitOr, BitOrAssign};
+
+use crate::aes_round::AesBackend;
+
+/// A set of flags. The values are the ones of the reference implementation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RandomXFlags(u32);
+
+impl RandomXFlags {
+    /// Light mode, portable code
+    pub const DEFAULT: Self = Self(0);
+    /// Allocate the memory with large pages
+    pub const LARGE_PAGES: Self = Self(1);
+    /// Use the AES instructions of the CPU
+    pub const HARD_AES: Self = Self(2);
+    /// Fast mode: the Dataset items are read from a precomputed Dataset
+    pub const FULL_MEM: Self = Self(4);
+    /// Compile the programs to machine code
+    pub const JIT: Self = Self(8);
+    /// Never map the JIT memory both writable and executable (W^X). The
+    /// memory is made writable to copy each program, then executable again.
+    ///
+    /// Without this flag, the JIT memory stays writable and executable, like
+    /// in the reference implementation. This is a deliberate trade-off: it
+    /// saves two `mprotect` calls per program, 16 per hash, but a memory
+    /// corruption bug can then inject code which gets executed.
+    pub const SECURE: Self = Self(16);
+    /// Argon2 implementation using SSSE3.
+    ///
+    /// No-op, accepted for compatibility with the reference flags: the
+    /// `argon2` crate fills the Cache and selects its implementation itself.
+    pub const ARGON2_SSSE3: Self = Self(32);
+    /// Argon2 implementation using AVX2.
+    ///
+    /// No-op, accepted for compatibility with the reference flags: the
+    /// `argon2` crate fills the Cache and uses AVX2 when the CPU supports
+    /// it, whether this flag is set or not.
+    pub const ARGON2_AVX2: Self = Self(64);
+    /// [RandomXFlags::ARGON2_SSSE3] and [RandomXFlags::ARGON2_AVX2], both
+    /// no-ops
+    pub const ARGON2: Self = Self::ARGON2_SSSE3.union(Self::ARGON2_AVX2);
+
+    /// Return the raw value of the flags
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Return true if all the flags of `other` are set
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Return the flags set in `self` or in `other`
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Return the flags set both in `self` and in `other`
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// Return the flags recommended for the current CPU, like `randomx_get_flags`
+/// in the reference implementation. Only [RandomXFlags::HARD_AES] is
+/// detected. [RandomXFlags::JIT] is set when the crate is built with the
+/// `jit` feature.
+pub fn get_flags() -> RandomXFlags {
+    let mut flags = RandomXFlags::DEFAULT;
+    if AesBackend::hardware_available() {
+        flags |= RandomXFlags::HARD_AES;
+    }
+    if cfg!(feature = "jit") {
+        flags |= RandomXFlags::JIT;
+    }
+    flags
+}
+
+impl BitOr for RandomXFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl BitOrAssign for RandomXFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0
+    }
+}
@@ -35,3 +35,186 @@ pub fn static_exponent(v: u64) -> u64 {
 pub fn float_mask(v: u64) -> u64 {
     (v & ((1 << 22) - 1)) | (static_exponent(v))
 }
+
+/// Sign-extend a 32-bit immediate to 64 bits, as done by
+/// `signExtend2sCompl` in the reference implementation.
+pub fn sign_extend_2s_compl(x: u32) -> u64 {
+    x as i32 as i64 as u64
+}
+
+/// Return true if `x` is zero or a power of two. The divisor of `IMUL_RCP`
+/// must not satisfy this condition.
+pub fn is_zero_or_power_of_2(x: u64) -> bool {
+    (x & x.wrapping_sub(1)) == 0
+}
+
+/// High 64 bits of the unsigned 128-bit product of `a` and `b`.
+pub fn mulh(a: u64, b: u64) -> u64 {
+    ((a as u128 * b as u128) >> 64) as u64
+}
+
+/// High 64 bits of the signed 128-bit product of `a` and `b`.
+pub fn smulh(a: u64, b: u64) -> u64 {
+    ((a as i64 as i128 * b as i64 as i128) >> 64) as u64
+}
+
+/// Compute `2^x / divisor` for the highest integer `x` such that the result
+/// fits in 64 bits. This is `randomx_reciprocal` from the reference
+/// implementation, used by the `IMUL_RCP` instruction.
+/// The divisor must not be zero or a power of two.
+pub(crate) fn reciprocal(divisor: u64) -> u64 {
+    assert!(divisor != 0);
+    let p2exp63: u64 = 1 << 63;
+    let mut quotient: u64 = p2exp63 / divisor;
+    let mut remainder: u64 = p2exp63 % divisor;
+
+    // Position of the highest set bit in the divisor
+    let bsr = 64 - divisor.leading_zeros();
+
+    for _ in 0..bsr {
+        if remainder >= divisor - remainder {
+            quotient = quotient.wrapping_mul(2).wrapping_add(1);
+            remainder = remainder.wrapping_mul(2).wrapping_sub(divisor);
+        } else {
+            quotient = quotient.wrapping_mul(2);
+            remainder = remainder.wrapping_mul(2);
+        }
+    }
+    quotient
+}
+
+/// Rounding modes of the floating point unit, selected by the `fprc`
+/// register. The values match the encoding of the rounding control field of
+/// the x86 MXCSR register.
+pub const ROUNDING_MODE_NEAREST: u32 = 0;
+pub const ROUNDING_MODE_DOWN: u32 = 1;
+pub const ROUNDING_MODE_UP: u32 = 2;
+pub const ROUNDING_MODE_ZERO: u32 = 3;
+
+fn next_up(x: f64) -> f64 {
+    if x.is_nan() || x == f64::INFINITY {
+        return x;
+    }
+    if x == 0.0 {
+        return f64::from_bits(1);
+    }
+    let bits = x.to_bits();
+    if x > 0.0 {
+        f64::from_bits(bits + 1)
+    } else {
+        f64::from_bits(bits - 1)
+    }
+}
+
+fn next_down(x: f64) -> f64 {
+    -next_up(-x)
+}
+
+/// Adjust the infinity `x` returned by an operation on finite operands
+/// which overflowed: directed roundings may saturate to the largest finite
+/// value instead. An infinity computed from an infinite operand is exact and
+/// must be kept.
+fn round_overflow(x: f64, mode: u32) -> f64 {
+    let saturate = match mode {
+        ROUNDING_MODE_DOWN => x > 0.0,
+        ROUNDING_MODE_UP => x < 0.0,
+        ROUNDING_MODE_ZERO => true,
+        _ => false,
+    };
+    if saturate {
+        f64::MAX.copysign(x)
+    } else {
+        x
+    }
+}
+
+/// Adjust the finite result `x` computed with round-to-nearest to the
+/// requested rounding `mode`. `error` must have the sign of `exact - x`,
+/// where `exact` is the infinitely precise result of the operation.
+fn round_result(x: f64, error: f64, mode: u32) -> f64 {
+    match mode {
+        ROUNDING_MODE_DOWN if error < 0.0 => next_down(x),
+        ROUNDING_MODE_UP if error > 0.0 => next_up(x),
+        ROUNDING_MODE_ZERO if x > 0.0 && error < 0.0 => next_down(x),
+        ROUNDING_MODE_ZERO if x < 0.0 && error > 0.0 => next_up(x),
+        _ => x,
+    }
+}
+
+/// Floating point addition using the rounding `mode`.
+///
+/// The result is first computed with the default round-to-nearest mode and
+/// the exact rounding error is recovered with the TwoSum algorithm, which
+/// lets us emulate the other IEEE 754 rounding modes portably.
+pub fn fadd(a: f64, b: f64, mode: u32) -> f64 {
+    let s = a + b;
+    if mode == ROUNDING_MODE_NEAREST {
+        return s;
+    }
+    if s == 0.0 {
+        // Exact zero: the sign is negative only when rounding down, unless
+        // both operands are positive zeroes.
+        if mode == ROUNDING_MODE_DOWN && !(a.is_sign_positive() && b.is_sign_positive()) {
+            return -0.0;
+        }
+        return s;
+    }
+    if s.is_infinite() {
+        if a.is_finite() && b.is_finite() {
+            return round_overflow(s, mode);
+        }
+        return s;
+    }
+    let bb = s - a;
+    let error = (a - (s - bb)) + (b - bb);
+    round_result(s, error, mode)
+}
+
+/// Floating point subtraction using the rounding `mode`.
+pub fn fsub(a: f64, b: f64, mode: u32) -> f64 {
+    fadd(a, -b, mode)
+}
+
+/// Floating point multiplication using the rounding `mode`.
+pub fn fmul(a: f64, b: f64, mode: u32) -> f64 {
+    let p = a * b;
+    if mode == ROUNDING_MODE_NEAREST || p == 0.0 {
+        return p;
+    }
+    if p.is_infinite() {
+        if a.is_finite() && b.is_finite() {
+            return round_overflow(p, mode);
+        }
+        return p;
+    }
+    let error = a.mul_add(b, -p);
+    round_result(p, error, mode)
+}
+
+/// Floating point division using the rounding `mode`.
+pub fn fdiv(a: f64, b: f64, mode: u32) -> f64 {
+    let q = a / b;
+    if mode == ROUNDING_MODE_NEAREST || q == 0.0 || b.is_infinite() {
+        return q;
+    }
+    if q.is_infinite() {
+        if a.is_finite() && b != 0.0 {
+            return round_overflow(q, mode);
+        }
+        return q;
+    }
+    // r = a - q * b is exact. The sign of (a / b - q) is the sign of r / b.
+    let r = (-q).mul_add(b, a);
+    let error = if b > 0.0 { r } else { -r };
+    round_result(q, error, mode)
+}
+
+/// Floating point square root using the rounding `mode`.
+pub fn fsqrt(a: f64, mode: u32) -> f64 {
+    let s = a.sqrt();
+    if mode == ROUNDING_MODE_NEAREST || s == 0.0 || !s.is_finite() {
+        return s;
+    }
+    let error = (-s).mul_add(s, a);
+    round_result(s, error, mode)
+}
@@ -0,0 +1,1087 @@
+//! x86-64 JIT compiler for the RandomX programs, equivalent to the
+//! `JitCompilerX86` of the reference implementation. Only built with the
+//! `jit` feature. The SuperscalarHash programs used to compute the Dataset
+//! items are compiled too, see [SuperscalarHashJit].
+//!
+//! The whole loop described in
+//! [4.6.2](https://github.com/tevador/RandomX/blob/master/doc/specs.md#462-loop-execution)
+//! is compiled, not only the program. The registers of the VM are mapped to
+//! the registers of the CPU:
+//!
+//! | VM         | x86-64                                        |
+//! |------------|-----------------------------------------------|
+//! | r0-r7      | r8-r15                                        |
+//! | f0-f3      | xmm0-xmm3                                     |
+//! | e0-e3      | xmm4-xmm7                                     |
+//! | a0-a3      | xmm8-xmm11                                    |
+//! | ma, mx     | rbp (ma in the low half, mx in the high half) |
+//! | scratchpad | rsi                                           |
+//! | dataset    | rdi                                           |
+//! | context    | rbx                                           |
+//!
+//! rax, rcx, rdx and xmm12 are temporaries, xmm13-xmm15 hold the constant
+//! masks. The rounding mode `fprc` is stored in the MXCSR register.
+use std::mem::{offset_of, size_of_val};
+use std::ptr;
+
+use argon2::Block;
+
+use crate::cache::Cache;
+use crate::parameters::{
+    DYNAMIC_MANTISSA_MASK, FSCAL_MASK, RANDOMX_CACHE_LINE_ASSIGN_MASK, RANDOMX_CACHE_LINE_SIZE,
+    RANDOMX_CACHE_SIZE, RANDOMX_PROGRAM_SIZE, SCRATCHPAD_L3_MASK64, SUPERSCALAR_ADD1,
+    SUPERSCALAR_ADD2, SUPERSCALAR_ADD3, SUPERSCALAR_ADD4, SUPERSCALAR_ADD5, SUPERSCALAR_ADD6,
+    SUPERSCALAR_ADD7, SUPERSCALAR_MUL0,
+};
+use crate::superscalar::{SuperscalarInstructionType, SuperscalarProgram};
+use crate::vm::{
+    self, ByteCode, DatasetMemory, EncodedInstruction, Instruction, RegisterFile, VMEnvironment,
+};
+use crate::Error;
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("the jit feature is only supported on x86-64 unix systems");
+
+/// Size of the executable buffer. A compiled program is about 5 KiB.
+const CODE_SIZE: usize = 64 * 1024;
+
+/// MXCSR value with all the exceptions masked, to which the rounding mode is
+/// added
+const MXCSR_DEFAULT: u32 = 0x1F80;
+const MXCSR_ROUNDING_SHIFT: u32 = 13;
+
+// x86-64 general purpose registers
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSP: u8 = 4;
+const RBP: u8 = 5;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R8: u8 = 8;
+
+// xmm registers used besides the f, e and a groups
+const XMM_TMP: u8 = 12;
+const XMM_MANTISSA_MASK: u8 = 13;
+const XMM_EXPONENT_MASK: u8 = 14;
+const XMM_SCALE_MASK: u8 = 15;
+
+/// Register of the CPU holding the integer register `r` of the VM
+fn r(index: usize) -> u8 {
+    R8 + index as u8
+}
+
+/// The state shared between the compiled code and Rust. The registers of
+/// the VM are copied as they are: the layout of [RegisterFile] matches the
+/// xmm registers.
+#[repr(C)]
+struct JitContext {
+    registers: RegisterFile,
+    mantissa_mask: [u64; 2],
+    exponent_mask: [u64; 2],
+    scale_mask: [u64; 2],
+    scratchpad: *mut u8,
+    /// Fast mode: Dataset memory at `dataset_offset`
+    dataset: *const u8,
+    /// Light mode: Cache used to compute the Dataset items
+    cache: *const Cache,
+    dataset_offset: u64,
+    /// ma in the low half, mx in the high half, as in rbp
+    memory_registers: u64,
+    sp_addr0: u32,
+    sp_addr1: u32,
+    ic: u32,
+    fprc: u32,
+    host_mxcsr: u32,
+    /// Light mode: Dataset item computed by `dataset_item`
+    item: [u64; 8],
+    /// Light mode: registers saved around the call to `dataset_item`
+    saved_gpr: [u64; 8],
+    saved_xmm: [[u64; 2]; 16],
+    dataset_item: extern "sysv64" fn(*mut JitContext, u64),
+}
+
+/// Called by the compiled code in light mode to compute the Dataset item
+/// `item_number` into the context
+extern "sysv64" fn dataset_item(ctx: *mut JitContext, item_number: u64) {
+    // SAFETY: the compiled code passes the context it was called with, in
+    // which `cache` points to the Cache borrowed by `JitCompiler::run`.
+    let ctx = unsafe { &mut *ctx };
+    ctx.item = unsafe { &*ctx.cache }.init_dataset_item(item_number);
+}
+
+/// Offset of a field of the context, as a displacement from rbx
+macro_rules! ctx {
+    ($field:ident) => {
+        offset_of!(JitContext, $field) as i32
+    };
+    ($field:ident, $index:expr) => {
+        (offset_of!(JitContext, $field) + 8 * $index) as i32
+    };
+    (registers.$field:ident, $index:expr) => {
+        (offset_of!(JitContext, registers) + offset_of!(RegisterFile, $field) + 8 * $index) as i32
+    };
+}
+
+/// A memory buffer mapped with `mmap`, in which the compiled code is
+/// written.
+///
+/// By default, the buffer is mapped readable, writable and executable once
+/// and for all. In secure mode, like with `RANDOMX_FLAG_SECURE` in the
+/// reference implementation, it is never writable and executable at the
+/// same time: it is made writable with `mprotect` to copy the code, then
+/// executable again.
+pub struct CodeBuffer {
+    ptr: *mut u8,
+    size: usize,
+    secure: bool,
+}
+
+// SAFETY: the buffer owns its mapping
+unsafe impl Send for CodeBuffer {}
+unsafe impl Sync for CodeBuffer {}
+
+impl CodeBuffer {
+    /// Map `size` bytes of memory. In secure mode, it is only readable and
+    /// writable until the first call to [CodeBuffer::write].
+    pub fn new(size: usize, secure: bool) -> Result<Self, Error> {
+        let prot = if secure {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC
+        };
+        // SAFETY: anonymous mapping, no existing memory is affected
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                prot,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::AllocationFailed { size });
+        }
+        Ok(CodeBuffer {
+            ptr: ptr as *mut u8,
+            size,
+            secure,
+        })
+    }
+
+    /// Return true if the buffer is never writable and executable at the
+    /// same time
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    /// Replace the content of the buffer by `code`. The buffer is executable
+    /// afterwards.
+    ///
+    /// Fails with [Error::AllocationFailed] if `code` does not fit the
+    /// buffer, and with [Error::ProtectionFailed] if the protection of the
+    /// buffer cannot be changed in secure mode. The buffer must not be
+    /// executed after a failure.
+    pub fn write(&mut self, code: &[u8]) -> Result<(), Error> {
+        if code.len() > self.size {
+            return Err(Error::AllocationFailed { size: code.len() });
+        }
+        if self.secure {
+            self.protect(libc::PROT_READ | libc::PROT_WRITE)?;
+        }
+        // SAFETY: the buffer is writable and large enough
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), self.ptr, code.len()) };
+        if self.secure {
+            self.protect(libc::PROT_READ | libc::PROT_EXEC)?;
+        }
+        Ok(())
+    }
+
+    /// Return the address of the beginning of the buffer
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    fn protect(&mut self, prot: libc::c_int) -> Result<(), Error> {
+        // SAFETY: the range is the mapping owned by the buffer
+        let res = unsafe { libc::mprotect(self.ptr as *mut libc::c_void, self.size, prot) };
+        if res != 0 {
+            return Err(Error::ProtectionFailed);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is owned by the buffer and not used anymore
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.size) };
+    }
+}
+
+/// A memory operand
+#[derive(Clone, Copy)]
+enum Mem {
+    /// `[base + disp]`
+    Disp { base: u8, disp: i32 },
+    /// `[base + index * 2^scale + disp]`
+    Sib {
+        base: u8,
+        index: u8,
+        scale: u8,
+        disp: i32,
+    },
+}
+
+/// Minimal x86-64 encoder. The operands are register numbers: 0-15 for the
+/// general purpose registers (rax-r15) or for the xmm registers, depending
+/// on the instruction.
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    fn pos(&self) -> usize {
+        self.code.len()
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, value: u32) {
+        self.emit(&value.to_le_bytes());
+    }
+
+    fn rex(&mut self, w: bool, reg: u8, index: u8, base: u8) {
+        let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | (base >> 3);
+        if rex != 0x40 {
+            self.emit(&[rex]);
+        }
+    }
+
+    /// Instruction with a register operand in `ModRM.reg` and a register
+    /// operand in `ModRM.rm`. `prefix` is the mandatory prefix, if any.
+    fn rr(&mut self, w: bool, prefix: &[u8], opcode: &[u8], reg: u8, rm: u8) {
+        self.emit(prefix);
+        self.rex(w, reg, 0, rm);
+        self.emit(opcode);
+        self.emit(&[0xC0 | (reg & 7) << 3 | (rm & 7)]);
+    }
+
+    /// Instruction with a register operand in `ModRM.reg` and a memory
+    /// operand
+    fn rm(&mut self, w: bool, prefix: &[u8], opcode: &[u8], reg: u8, mem: Mem) {
+        self.emit(prefix);
+        match mem {
+            Mem::Disp { base, disp } => {
+                self.rex(w, reg, 0, base);
+                self.emit(opcode);
+                self.emit(&[0x80 | (reg & 7) << 3 | (base & 7)]);
+                if base & 7 == RSP {
+                    self.emit(&[0x24]);
+                }
+                self.emit_u32(disp as u32);
+            }
+            Mem::Sib {
+                base,
+                index,
+                scale,
+                disp,
+            } => {
+                debug_assert_ne!(index, RSP);
+                self.rex(w, reg, index, base);
+                self.emit(opcode);
+                // rbp and r13 can only be used as base with a displacement
+                let with_disp = disp != 0 || base & 7 == RBP;
+                let mode = if with_disp { 0x80 } else { 0x00 };
+                self.emit(&[mode | (reg & 7) << 3 | 0b100]);
+                self.emit(&[scale << 6 | (index & 7) << 3 | (base & 7)]);
+                if with_disp {
+                    self.emit_u32(disp as u32);
+                }
+            }
+        }
+    }
+
+    /// Instruction with an opcode extension and a register operand, followed
+    /// by a 32-bit immediate value
+    fn ri32(&mut self, w: bool, opcode: u8, ext: u8, rm: u8, imm: u32) {
+        self.rr(w, &[], &[opcode], ext, rm);
+        self.emit_u32(imm);
+    }
+
+    /// Same as [Assembler::ri32] with an 8-bit immediate value
+    fn ri8(&mut self, w: bool, opcode: u8, ext: u8, rm: u8, imm: u8) {
+        self.rr(w, &[], &[opcode], ext, rm);
+        self.emit(&[imm]);
+    }
+
+    fn push(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.emit(&[0x50 | (reg & 7)]);
+    }
+
+    fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, 0, reg);
+        self.emit(&[0x58 | (reg & 7)]);
+    }
+
+    /// `mov dst, src` on 64-bit registers
+    fn mov(&mut self, dst: u8, src: u8) {
+        self.rr(true, &[], &[0x8B], dst, src);
+    }
+
+    /// `mov dst, [mem]` (64 bits)
+    fn load(&mut self, dst: u8, mem: Mem) {
+        self.rm(true, &[], &[0x8B], dst, mem);
+    }
+
+    /// `mov [mem], src` (64 bits)
+    fn store(&mut self, mem: Mem, src: u8) {
+        self.rm(true, &[], &[0x89], src, mem);
+    }
+
+    /// `movupd xmm, [mem]`
+    fn load_xmm(&mut self, xmm: u8, mem: Mem) {
+        self.rm(false, &[0x66], &[0x0F, 0x10], xmm, mem);
+    }
+
+    /// `movupd [mem], xmm`
+    fn store_xmm(&mut self, mem: Mem, xmm: u8) {
+        self.rm(false, &[0x66], &[0x0F, 0x11], xmm, mem);
+    }
+
+    /// `ldmxcsr [mem]`
+    fn ldmxcsr(&mut self, mem: Mem) {
+        self.rm(false, &[], &[0x0F, 0xAE], 2, mem);
+    }
+
+    /// `stmxcsr [mem]`
+    fn stmxcsr(&mut self, mem: Mem) {
+        self.rm(false, &[], &[0x0F, 0xAE], 3, mem);
+    }
+
+    /// Jump with a 32-bit displacement to `target`, `opcode` being the
+    /// opcode of the near jump
+    fn jump(&mut self, opcode: &[u8], target: usize) {
+        self.emit(opcode);
+        let end = self.pos() + 4;
+        self.emit_u32((target as i64 - end as i64) as u32);
+    }
+
+    /// Emit a jump with a 32-bit displacement to be set later with
+    /// [Assembler::patch_jump]. Return the position of the displacement.
+    fn forward_jump(&mut self, opcode: &[u8]) -> usize {
+        self.emit(opcode);
+        self.emit_u32(0);
+        self.pos() - 4
+    }
+
+    fn patch_jump(&mut self, displacement_pos: usize, target: usize) {
+        let disp = (target as i64 - (displacement_pos + 4) as i64) as u32;
+        self.code[displacement_pos..displacement_pos + 4].copy_from_slice(&disp.to_le_bytes());
+    }
+}
+
+fn ctx(disp: i32) -> Mem {
+    Mem::Disp { base: RBX, disp }
+}
+
+/// Scratchpad operand at the address in the register `reg`
+fn scratchpad(reg: u8) -> Mem {
+    Mem::Sib {
+        base: RSI,
+        index: reg,
+        scale: 0,
+        disp: 0,
+    }
+}
+
+/// Compiles the programs of a [VMEnvironment] to machine code and runs them.
+/// A compiler is attached to an environment with its `jit` field.
+pub struct JitCompiler {
+    buffer: CodeBuffer,
+    asm: Assembler,
+    /// Position of the code of each instruction, used by `CBRANCH`
+    instruction_offsets: Vec<usize>,
+}
+
+impl JitCompiler {
+    /// Allocate the executable memory of the compiler. In secure mode, see
+    /// [RandomXFlags::SECURE](crate::flags::RandomXFlags::SECURE), the
+    /// memory is made writable around the compilation of each program. It is
+    /// made executable a first time here, so that a failure is reported
+    /// early.
+    pub fn new(secure: bool) -> Result<Self, Error> {
+        let mut buffer = CodeBuffer::new(CODE_SIZE, secure)?;
+        buffer.write(&[])?;
+        Ok(JitCompiler {
+            buffer,
+            // The code never exceeds the size of the buffer, so compiling a
+            // program does not allocate
+            asm: Assembler {
+                code: Vec::with_capacity(CODE_SIZE),
+            },
+            instruction_offsets: Vec::with_capacity(RANDOMX_PROGRAM_SIZE as usize),
+        })
+    }
+
+    /// Compile the program loaded in `env`, then execute it until `env.ic`
+    /// reaches zero. The results are the same as with [VMEnvironment::run]
+    /// using the interpreter.
+    ///
+    /// Fails if the code cannot be written, see [CodeBuffer::write]. `env`
+    /// is left untouched in that case.
+    pub fn run(&mut self, env: &mut VMEnvironment, memory: DatasetMemory) -> Result<(), Error> {
+        self.compile(env, memory)?;
+
+        let (dataset, cache) = match memory {
+            DatasetMemory::Light(cache) => (ptr::null(), cache as *const Cache),
+            DatasetMemory::Fast(dataset) => (
+                dataset.memory[env.dataset_offset as usize..].as_ptr(),
+                ptr::null(),
+            ),
+        };
+        let mut ctx = JitContext {
+            registers: env.registers,
+            mantissa_mask: [DYNAMIC_MANTISSA_MASK; 2],
+            exponent_mask: env.configuration.emask,
+            scale_mask: [FSCAL_MASK; 2],
+            scratchpad: env.scratchpad.as_mut_ptr(),
+            dataset,
+            cache,
+            dataset_offset: env.dataset_offset,
+            memory_registers: env.ma as u64 | (env.mx as u64) << 32,
+            sp_addr0: env.sp_addr0,
+            sp_addr1: env.sp_addr1,
+            ic: env.ic,
+            fprc: env.fprc,
+            host_mxcsr: 0,
+            item: [0; 8],
+            saved_gpr: [0; 8],
+            saved_xmm: [[0; 2]; 16],
+            dataset_item,
+        };
+
+        // SAFETY: the buffer contains the code generated by `compile`, which
+        // follows the System V calling convention. It only accesses the
+        // scratchpad and the Dataset at masked addresses, and the context.
+        unsafe {
+            let code: extern "sysv64" fn(*mut JitContext) =
+                std::mem::transmute(self.buffer.as_ptr());
+            code(&mut ctx);
+        }
+
+        env.registers = ctx.registers;
+        env.ma = ctx.memory_registers as u32;
+        env.mx = (ctx.memory_registers >> 32) as u32;
+        env.sp_addr0 = ctx.sp_addr0;
+        env.sp_addr1 = ctx.sp_addr1;
+        env.ic = ctx.ic;
+        env.fprc = ctx.fprc;
+        Ok(())
+    }
+
+    fn compile(&mut self, env: &VMEnvironment, memory: DatasetMemory) -> Result<(), Error> {
+        self.asm.code.clear();
+        self.instruction_offsets.clear();
+        let config = &env.configuration;
+        let read_reg = |reg: u32| r(reg as usize);
+
+        self.generate_prologue();
+        // cmp dword [rbx + ic], 0; je epilogue
+        self.asm.rm(false, &[], &[0x83], 7, ctx(ctx!(ic)));
+        self.asm.emit(&[0]);
+        let exit_jump = self.asm.forward_jump(&[0x0F, 0x84]);
+
+        let loop_begin = self.asm.pos();
+        self.generate_loop_load(read_reg(config.read_reg0), read_reg(config.read_reg1));
+        for bc in env.bytecode.iter() {
+            self.instruction_offsets.push(self.asm.pos());
+            self.generate_instruction(bc);
+        }
+        self.generate_dataset_read(
+            read_reg(config.read_reg2),
+            read_reg(config.read_reg3),
+            memory,
+        );
+        self.generate_loop_store();
+        // sub dword [rbx + ic], 1; jnz loop_begin
+        self.asm.rm(false, &[], &[0x83], 5, ctx(ctx!(ic)));
+        self.asm.emit(&[1]);
+        self.asm.jump(&[0x0F, 0x85], loop_begin);
+
+        let epilogue = self.asm.pos();
+        self.asm.patch_jump(exit_jump, epilogue);
+        self.generate_epilogue();
+
+        self.buffer.write(&self.asm.code)
+    }
+
+    fn generate_prologue(&mut self) {
+        let asm = &mut self.asm;
+        for reg in [RBX, RBP, 12, 13, 14, 15] {
+            asm.push(reg);
+        }
+        // Align the stack on 16 bytes for the calls in light mode
+        asm.ri8(true, 0x83, 5, RSP, 8);
+        asm.mov(RBX, RDI);
+
+        asm.stmxcsr(ctx(ctx!(host_mxcsr)));
+        Self::generate_set_rounding_mode(asm, ctx(ctx!(fprc)));
+
+        for i in 0..8 {
+            asm.load(r(i), ctx(ctx!(registers.r, i)));
+        }
+        for i in 0..4 {
+            asm.load_xmm(i as u8, ctx(ctx!(registers.f, 2 * i)));
+            asm.load_xmm(4 + i as u8, ctx(ctx!(registers.e, 2 * i)));
+            asm.load_xmm(8 + i as u8, ctx(ctx!(registers.a, 2 * i)));
+        }
+        asm.load_xmm(XMM_MANTISSA_MASK, ctx(ctx!(mantissa_mask)));
+        asm.load_xmm(XMM_EXPONENT_MASK, ctx(ctx!(exponent_mask)));
+        asm.load_xmm(XMM_SCALE_MASK, ctx(ctx!(scale_mask)));
+        asm.load(RSI, ctx(ctx!(scratchpad)));
+        asm.load(RDI, ctx(ctx!(dataset)));
+        asm.load(RBP, ctx(ctx!(memory_registers)));
+        // eax and edx hold spAddr0 and spAddr1
+        asm.rm(false, &[], &[0x8B], RAX, ctx(ctx!(sp_addr0)));
+        asm.rm(false, &[], &[0x8B], RDX, ctx(ctx!(sp_addr1)));
+    }
+
+    /// Set the rounding mode of MXCSR to the 32-bit value at `mem`, which is
+    /// one of the `ROUNDING_MODE_*` constants. The RandomX rounding modes
+    /// have the same encoding as the MXCSR ones.
+    fn generate_set_rounding_mode(asm: &mut Assembler, mem: Mem) {
+        // mov eax, [mem]; shl eax, 13; or eax, MXCSR_DEFAULT
+        asm.rm(false, &[], &[0x8B], RAX, mem);
+        asm.ri8(false, 0xC1, 4, RAX, MXCSR_ROUNDING_SHIFT as u8);
+        asm.ri32(false, 0x81, 1, RAX, MXCSR_DEFAULT);
+        // push rax; ldmxcsr [rsp]; pop rax
+        asm.push(RAX);
+        asm.ldmxcsr(Mem::Disp { base: RSP, disp: 0 });
+        asm.pop(RAX);
+    }
+
+    /// Compute the scratchpad addresses and load the registers from the
+    /// scratchpad. The addresses of the two blocks are pushed on the stack.
+    fn generate_loop_load(&mut self, read_reg0: u8, read_reg1: u8) {
+        let asm = &mut self.asm;
+        // spMix = r[readReg0] ^ r[readReg1]
+        asm.mov(RCX, read_reg0);
+        asm.rr(true, &[], &[0x33], RCX, read_reg1);
+        // spAddr0 ^= spMix; spAddr0 &= mask
+        asm.rr(false, &[], &[0x33], RAX, RCX);
+        asm.ri32(false, 0x81, 4, RAX, SCRATCHPAD_L3_MASK64 as u32);
+        // spAddr1 ^= spMix >> 32; spAddr1 &= mask
+        asm.ri8(true, 0xC1, 5, RCX, 32);
+        asm.rr(false, &[], &[0x33], RDX, RCX);
+        asm.ri32(false, 0x81, 4, RDX, SCRATCHPAD_L3_MASK64 as u32);
+
+        // lea rcx, [rsi + rax]; push rcx
+        asm.rm(true, &[], &[0x8D], RCX, scratchpad(RAX));
+        asm.push(RCX);
+        for i in 0..8 {
+            let mem = Mem::Disp {
+                base: RCX,
+                disp: 8 * i as i32,
+            };
+            asm.rm(true, &[], &[0x33], r(i), mem);
+        }
+
+        // lea rcx, [rsi + rdx]; push rcx
+        asm.rm(true, &[], &[0x8D], RCX, scratchpad(RDX));
+        asm.push(RCX);
+        for i in 0..8u8 {
+            let mem = Mem::Disp {
+                base: RCX,
+                disp: 8 * i as i32,
+            };
+            // cvtdq2pd xmm, [mem]
+            asm.rm(false, &[0xF3], &[0x0F, 0xE6], i, mem);
+            if i >= 4 {
+                // andps xmm, xmm13; orps xmm, xmm14
+                asm.rr(false, &[], &[0x0F, 0x54], i, XMM_MANTISSA_MASK);
+                asm.rr(false, &[], &[0x0F, 0x56], i, XMM_EXPONENT_MASK);
+            }
+        }
+    }
+
+    /// Read the Dataset item at `ma` into the integer registers and update
+    /// `ma` and `mx`
+    fn generate_dataset_read(&mut self, read_reg2: u8, read_reg3: u8, memory: DatasetMemory) {
+        let asm = &mut self.asm;
+        // mx ^= r[readReg2] ^ r[readReg3]; mx &= mask, into eax
+        asm.mov(RCX, read_reg2);
+        asm.rr(true, &[], &[0x33], RCX, read_reg3);
+        asm.mov(RAX, RBP);
+        asm.ri8(true, 0xC1, 5, RAX, 32);
+        asm.rr(false, &[], &[0x33], RAX, RCX);
+        asm.ri32(false, 0x81, 4, RAX, RANDOMX_CACHE_LINE_ASSIGN_MASK as u32);
+
+        match memory {
+            DatasetMemory::Fast(_) => {
+                // mov edx, ebp; xor r, [rdi + rdx + 8 * i]
+                asm.rr(false, &[], &[0x8B], RDX, RBP);
+                for i in 0..8 {
+                    let mem = Mem::Sib {
+                        base: RDI,
+                        index: RDX,
+                        scale: 0,
+                        disp: 8 * i as i32,
+                    };
+                    asm.rm(true, &[], &[0x33], r(i), mem);
+                }
+            }
+            DatasetMemory::Light(_) => {
+                // The caller-saved registers are saved in the context
+                let gprs = [RAX, RSI, RDI, 8, 9, 10, 11];
+                let xmms = (0..16).filter(|&xmm| xmm != XMM_TMP);
+                for (i, &reg) in gprs.iter().enumerate() {
+                    asm.store(ctx(ctx!(saved_gpr, i)), reg);
+                }
+                for xmm in xmms.clone() {
+                    asm.store_xmm(ctx(ctx!(saved_xmm, 2 * xmm as usize)), xmm);
+                }
+                // dataset_item(ctx, (dataset_offset + ma) / 64)
+                asm.rr(false, &[], &[0x8B], RSI, RBP);
+                asm.rm(true, &[], &[0x03], RSI, ctx(ctx!(dataset_offset)));
+                asm.ri8(true, 0xC1, 5, RSI, 6);
+                asm.mov(RDI, RBX);
+                asm.load(RAX, ctx(ctx!(dataset_item)));
+                // call rax
+                asm.rr(false, &[], &[0xFF], 2, RAX);
+                for (i, &reg) in gprs.iter().enumerate() {
+                    asm.load(reg, ctx(ctx!(saved_gpr, i)));
+                }
+                for xmm in xmms {
+                    asm.load_xmm(xmm, ctx(ctx!(saved_xmm, 2 * xmm as usize)));
+                }
+                for i in 0..8 {
+                    asm.rm(true, &[], &[0x33], r(i), ctx(ctx!(item, i)));
+                }
+            }
+        }
+        // Swap ma and mx: rbp = (ma << 32) | mx
+        asm.ri8(true, 0xC1, 4, RBP, 32);
+        asm.rr(true, &[], &[0x0B], RBP, RAX);
+    }
+
+    /// Store the registers into the scratchpad at the addresses pushed by
+    /// `generate_loop_load`
+    fn generate_loop_store(&mut self) {
+        let asm = &mut self.asm;
+        asm.pop(RCX);
+        for i in 0..8 {
+            let mem = Mem::Disp {
+                base: RCX,
+                disp: 8 * i as i32,
+            };
+            asm.store(mem, r(i));
+        }
+        asm.pop(RCX);
+        for i in 0..4u8 {
+            // xorpd f, e; movupd [rcx + 16 * i], f
+            asm.rr(false, &[0x66], &[0x0F, 0x57], i, 4 + i);
+            let mem = Mem::Disp {
+                base: RCX,
+                disp: 16 * i as i32,
+            };
+            asm.store_xmm(mem, i);
+        }
+        // The addresses of the next iterations only depend on spMix
+        asm.rr(false, &[], &[0x33], RAX, RAX);
+        asm.rr(false, &[], &[0x33], RDX, RDX);
+    }
+
+    fn generate_epilogue(&mut self) {
+        let asm = &mut self.asm;
+        for i in 0..8 {
+            asm.store(ctx(ctx!(registers.r, i)), r(i));
+        }
+        for i in 0..4 {
+            asm.store_xmm(ctx(ctx!(registers.f, 2 * i)), i as u8);
+            asm.store_xmm(ctx(ctx!(registers.e, 2 * i)), 4 + i as u8);
+        }
+        asm.store(ctx(ctx!(memory_registers)), RBP);
+        asm.rm(false, &[], &[0x89], RAX, ctx(ctx!(sp_addr0)));
+        asm.rm(false, &[], &[0x89], RDX, ctx(ctx!(sp_addr1)));
+
+        // fprc = (mxcsr >> 13) & 3
+        let top = Mem::Disp { base: RSP, disp: 0 };
+        asm.stmxcsr(top);
+        asm.rm(false, &[], &[0x8B], RAX, top);
+        asm.ri8(false, 0xC1, 5, RAX, MXCSR_ROUNDING_SHIFT as u8);
+        asm.ri32(false, 0x81, 4, RAX, 3);
+        asm.rm(false, &[], &[0x89], RAX, ctx(ctx!(fprc)));
+        asm.ldmxcsr(ctx(ctx!(host_mxcsr)));
+
+        asm.ri8(true, 0x83, 0, RSP, 8);
+        for reg in [15, 14, 13, 12, RBP, RBX] {
+            asm.pop(reg);
+        }
+        asm.emit(&[0xC3]);
+    }
+
+    /// Return the memory operand of an instruction reading the scratchpad.
+    /// The address is computed into `tmp` when it depends on a register.
+    fn memory_operand(asm: &mut Assembler, bc: &ByteCode, tmp: u8) -> Mem {
+        if bc.src_imm {
+            Mem::Disp {
+                base: RSI,
+                disp: (bc.imm & bc.mem_mask) as i32,
+            }
+        } else {
+            Self::generate_address(asm, r(bc.src), bc, tmp);
+            scratchpad(tmp)
+        }
+    }
+
+    /// `lea tmp, [reg + imm]; and tmp, mask` on 32-bit registers
+    fn generate_address(asm: &mut Assembler, reg: u8, bc: &ByteCode, tmp: u8) {
+        let mem = Mem::Disp {
+            base: reg,
+            disp: bc.imm as i32,
+        };
+        asm.rm(false, &[], &[0x8D], tmp, mem);
+        asm.ri32(false, 0x81, 4, tmp, bc.mem_mask as u32);
+    }
+
+    fn generate_instruction(&mut self, bc: &ByteCode) {
+        let asm = &mut self.asm;
+        let dst = r(bc.dst);
+        let src = r(bc.src);
+        // Registers of the floating point instructions
+        let f = bc.dst as u8;
+        let e = 4 + bc.dst as u8;
+        let a = 8 + bc.src as u8;
+        match bc.instruction {
+            Instruction::IADD_RS => {
+                // lea dst, [dst + src * 2^shift + imm]
+                let mem = Mem::Sib {
+                    base: dst,
+                    index: src,
+                    scale: bc.shift as u8,
+                    disp: bc.imm as i32,
+                };
+                asm.rm(true, &[], &[0x8D], dst, mem);
+            }
+            Instruction::IADD_M => {
+                let mem = Self::memory_operand(asm, bc, RAX);
+                asm.rm(true, &[], &[0x03], dst, mem);
+            }
+            Instruction::ISUB_R if bc.src_imm => asm.ri32(true, 0x81, 5, dst, bc.imm as u32),
+            Instruction::ISUB_R => asm.rr(true, &[], &[0x2B], dst, src),
+            Instruction::ISUB_M => {
+                let mem = Self::memory_operand(asm, bc, RAX);
+                asm.rm(true, &[], &[0x2B], dst, mem);
+            }
+            Instruction::IMUL_R if bc.src_imm => {
+                if bc.imm as i64 == bc.imm as i32 as i64 {
+                    // imul dst, dst, imm32
+                    asm.rr(true, &[], &[0x69], dst, dst);
+                    asm.emit_u32(bc.imm as u32);
+                } else {
+                    // mov rax, imm64; imul dst, rax
+                    asm.rex(true, 0, 0, RAX);
+                    asm.emit(&[0xB8]);
+                    asm.emit(&bc.imm.to_le_bytes());
+                    asm.rr(true, &[], &[0x0F, 0xAF], dst, RAX);
+                }
+            }
+            Instruction::IMUL_R => asm.rr(true, &[], &[0x0F, 0xAF], dst, src),
+            Instruction::IMUL_M => {
+                let mem = Self::memory_operand(asm, bc, RAX);
+                asm.rm(true, &[], &[0x0F, 0xAF], dst, mem);
+            }
+            Instruction::IMULH_R | Instruction::ISMULH_R => {
+                // mov rax, dst; mul src (imul src); mov dst, rdx
+                let ext = if bc.instruction == Instruction::IMULH_R {
+                    4
+                } else {
+                    5
+                };
+                asm.mov(RAX, dst);
+                asm.rr(true, &[], &[0xF7], ext, src);
+                asm.mov(dst, RDX);
+            }
+            Instruction::IMULH_M | Instruction::ISMULH_M => {
+                let ext = if bc.instruction == Instruction::IMULH_M {
+                    4
+                } else {
+                    5
+                };
+                let mem = Self::memory_operand(asm, bc, RCX);
+                asm.mov(RAX, dst);
+                asm.rm(true, &[], &[0xF7], ext, mem);
+                asm.mov(dst, RDX);
+            }
+            // Compiled as IMUL_R
+            Instruction::IMUL_RCP => unreachable!(),
+            Instruction::INEG_R => asm.rr(true, &[], &[0xF7], 3, dst),
+            Instruction::IXOR_R if bc.src_imm => asm.ri32(true, 0x81, 6, dst, bc.imm as u32),
+            Instruction::IXOR_R => asm.rr(true, &[], &[0x33], dst, src),
+            Instruction::IXOR_M => {
+                let mem = Self::memory_operand(asm, bc, RAX);
+                asm.rm(true, &[], &[0x33], dst, mem);
+            }
+            Instruction::IROR_R | Instruction::IROL_R => {
+                let ext = if bc.instruction == Instruction::IROR_R {
+                    1
+                } else {
+                    0
+                };
+                if bc.src_imm {
+                    asm.ri8(true, 0xC1, ext, dst, (bc.imm & 63) as u8);
+                } else {
+                    // mov ecx, src; ror dst, cl
+                    asm.rr(false, &[], &[0x8B], RCX, src);
+                    asm.rr(true, &[], &[0xD3], ext, dst);
+                }
+            }
+            // xchg dst, src
+            Instruction::ISWAP_R => asm.rr(true, &[], &[0x87], src, dst),
+            // shufpd xmm, xmm, 1
+            Instruction::FSWAP_R => {
+                asm.rr(false, &[0x66], &[0x0F, 0xC6], f, f);
+                asm.emit(&[1]);
+            }
+            Instruction::FADD_R => asm.rr(false, &[0x66], &[0x0F, 0x58], f, a),
+            Instruction::FSUB_R => asm.rr(false, &[0x66], &[0x0F, 0x5C], f, a),
+            Instruction::FADD_M | Instruction::FSUB_M => {
+                let mem = Self::memory_operand(asm, bc, RAX);
+                // cvtdq2pd xmm12, [mem]; addpd (subpd) f, xmm12
+                asm.rm(false, &[0xF3], &[0x0F, 0xE6], XMM_TMP, mem);
+                let opcode = if bc.instruction == Instruction::FADD_M {
+                    0x58
+                } else {
+                    0x5C
+                };
+                asm.rr(false, &[0x66], &[0x0F, opcode], f, XMM_TMP);
+            }
+            // xorps f, xmm15
+            Instruction::FSCAL_R => asm.rr(false, &[], &[0x0F, 0x57], f, XMM_SCALE_MASK),
+            Instruction::FMUL_R => asm.rr(false, &[0x66], &[0x0F, 0x59], e, a),
+            Instruction::FDIV_M => {
+                let mem = Self::memory_operand(asm, bc, RAX);
+                // cvtdq2pd xmm12, [mem]; andps xmm12, xmm13; orps xmm12, xmm14
+                asm.rm(false, &[0xF3], &[0x0F, 0xE6], XMM_TMP, mem);
+                asm.rr(false, &[], &[0x0F, 0x54], XMM_TMP, XMM_MANTISSA_MASK);
+                asm.rr(false, &[], &[0x0F, 0x56], XMM_TMP, XMM_EXPONENT_MASK);
+                // divpd e, xmm12
+                asm.rr(false, &[0x66], &[0x0F, 0x5E], e, XMM_TMP);
+            }
+            Instruction::FSQRT_R => asm.rr(false, &[0x66], &[0x0F, 0x51], e, e),
+            Instruction::CBRANCH => {
+                // add dst, imm32; test dst, mask; jz target
+                debug_assert_eq!(bc.imm as i64, bc.imm as i32 as i64);
+                asm.ri32(true, 0x81, 0, dst, bc.imm as u32);
+                asm.ri32(true, 0xF7, 0, dst, bc.mem_mask as u32);
+                let target = self.instruction_offsets[(bc.target + 1) as usize];
+                asm.jump(&[0x0F, 0x84], target);
+            }
+            Instruction::CFROUND => {
+                // mov rax, src; ror rax, imm; and eax, 3
+                asm.mov(RAX, src);
+                asm.ri8(true, 0xC1, 1, RAX, bc.imm as u8);
+                asm.ri32(false, 0x81, 4, RAX, 3);
+                // shl eax, 13; or eax, MXCSR_DEFAULT; push rax; ldmxcsr [rsp]; pop rax
+                asm.ri8(false, 0xC1, 4, RAX, MXCSR_ROUNDING_SHIFT as u8);
+                asm.ri32(false, 0x81, 1, RAX, MXCSR_DEFAULT);
+                asm.push(RAX);
+                asm.ldmxcsr(Mem::Disp { base: RSP, disp: 0 });
+                asm.pop(RAX);
+            }
+            Instruction::ISTORE => {
+                // The address is computed from dst
+                Self::generate_address(asm, dst, bc, RAX);
+                asm.rm(true, &[], &[0x89], src, scratchpad(RAX));
+            }
+            Instruction::NOP => {}
+        }
+    }
+}
+
+/// Signature of the code generated by [SuperscalarHashJit]: compute the
+/// items `start_item..end_item` into `items` from the Cache memory
+type DatasetInitFn =
+    extern "sysv64" fn(memory: *const u8, items: *mut u8, start_item: u64, end_item: u64);
+
+/// The SuperscalarHash programs of a Cache compiled to a single function
+/// computing Dataset items, like `generateSuperscalarHash` and
+/// `generateDatasetInitCode` in the reference implementation. The
+/// reciprocals of the `IMUL_RCP` instructions are embedded in the code.
+pub struct SuperscalarHashJit {
+    buffer: CodeBuffer,
+}
+
+impl SuperscalarHashJit {
+    /// Compile the programs of a Cache. `reciprocals` are the values indexed
+    /// by the immediates of the `IMUL_RCP` instructions.
+    pub fn new(programs: &[SuperscalarProgram], reciprocals: &[u64]) -> Result<Self, Error> {
+        let mut asm = Assembler::default();
+        // Callee-saved registers, the stack is not used otherwise
+        for reg in [RBX, RBP, 12, 13, 14, 15] {
+            asm.push(reg);
+        }
+        // rdi: Cache memory, rsi: items, rbx: item number, rbp: end item
+        asm.mov(RBX, RDX);
+        asm.mov(RBP, RCX);
+        asm.rr(true, &[], &[0x3B], RBX, RBP);
+        let exit_jump = asm.forward_jump(&[0x0F, 0x83]);
+
+        let loop_begin = asm.pos();
+        // r0 = (itemNumber + 1) * SUPERSCALAR_MUL0
+        asm.rm(true, &[], &[0x8D], r(0), Mem::Disp { base: RBX, disp: 1 });
+        Self::mov_imm64(&mut asm, RAX, SUPERSCALAR_MUL0);
+        asm.rr(true, &[], &[0x0F, 0xAF], r(0), RAX);
+        // ri = r0 ^ SUPERSCALAR_ADDi
+        let constants = [
+            SUPERSCALAR_ADD1,
+            SUPERSCALAR_ADD2,
+            SUPERSCALAR_ADD3,
+            SUPERSCALAR_ADD4,
+            SUPERSCALAR_ADD5,
+            SUPERSCALAR_ADD6,
+            SUPERSCALAR_ADD7,
+        ];
+        for (i, constant) in constants.into_iter().enumerate() {
+            Self::mov_imm64(&mut asm, r(i + 1), constant);
+            asm.rr(true, &[], &[0x33], r(i + 1), r(0));
+        }
+
+        // rcx holds registerValue, then the offset of the cache line
+        asm.mov(RCX, RBX);
+        for program in programs {
+            let mask = RANDOMX_CACHE_SIZE / RANDOMX_CACHE_LINE_SIZE - 1;
+            asm.ri32(false, 0x81, 4, RCX, mask as u32);
+            asm.ri8(
+                true,
+                0xC1,
+                4,
+                RCX,
+                RANDOMX_CACHE_LINE_SIZE.trailing_zeros() as u8,
+            );
+            for instr in program.instructions() {
+                Self::generate_instruction(&mut asm, *instr, reciprocals);
+            }
+            for i in 0..8 {
+                let mem = Mem::Sib {
+                    base: RDI,
+                    index: RCX,
+                    scale: 0,
+                    disp: 8 * i as i32,
+                };
+                asm.rm(true, &[], &[0x33], r(i), mem);
+            }
+            asm.mov(RCX, r(program.addr_reg as usize));
+        }
+
+        for i in 0..8 {
+            let mem = Mem::Disp {
+                base: RSI,
+                disp: 8 * i as i32,
+            };
+            asm.store(mem, r(i));
+        }
+        // add rsi, 64; add rbx, 1; cmp rbx, rbp; jb loop_begin
+        asm.ri8(true, 0x83, 0, RSI, RANDOMX_CACHE_LINE_SIZE as u8);
+        asm.ri8(true, 0x83, 0, RBX, 1);
+        asm.rr(true, &[], &[0x3B], RBX, RBP);
+        asm.jump(&[0x0F, 0x82], loop_begin);
+
+        let epilogue = asm.pos();
+        asm.patch_jump(exit_jump, epilogue);
+        for reg in [15, 14, 13, 12, RBP, RBX] {
+            asm.pop(reg);
+        }
+        asm.emit(&[0xC3]);
+
+        // The code is written only once, the buffer does not need to stay
+        // writable
+        let mut buffer = CodeBuffer::new(asm.code.len(), true)?;
+        buffer.write(&asm.code)?;
+        Ok(SuperscalarHashJit { buffer })
+    }
+
+    /// Compute the consecutive items starting at `start_item` into `items`,
+    /// whose length must be a multiple of 64. `memory` must be the memory of
+    /// the Cache the programs come from.
+    pub(crate) fn init_items(&self, memory: &[Block], start_item: u64, items: &mut [u8]) {
+        assert_eq!(size_of_val(memory), RANDOMX_CACHE_SIZE as usize);
+        let count = (items.len() / RANDOMX_CACHE_LINE_SIZE as usize) as u64;
+        // SAFETY: the buffer contains the code generated by `new`, which
+        // reads the Cache at masked offsets, smaller than its size, and
+        // writes `count` items.
+        unsafe {
+            let code: DatasetInitFn = std::mem::transmute(self.buffer.as_ptr());
+            code(
+                memory.as_ptr() as *const u8,
+                items.as_mut_ptr(),
+                start_item,
+                start_item + count,
+            );
+        }
+    }
+
+    /// `mov reg, imm64`
+    fn mov_imm64(asm: &mut Assembler, reg: u8, imm: u64) {
+        asm.rex(true, 0, 0, reg);
+        asm.emit(&[0xB8 | (reg & 7)]);
+        asm.emit(&imm.to_le_bytes());
+    }
+
+    fn generate_instruction(asm: &mut Assembler, instr: EncodedInstruction, reciprocals: &[u64]) {
+        let dst = r(vm::dst(instr) as usize);
+        let src = r(vm::src(instr) as usize);
+        let imm = vm::imm32(instr);
+        match SuperscalarInstructionType::from_opcode(vm::opcode(instr)) {
+            SuperscalarInstructionType::ISUB_R => asm.rr(true, &[], &[0x2B], dst, src),
+            SuperscalarInstructionType::IXOR_R => asm.rr(true, &[], &[0x33], dst, src),
+            SuperscalarInstructionType::IADD_RS => {
+                // lea dst, [dst + src * 2^shift]
+                let mem = Mem::Sib {
+                    base: dst,
+                    index: src,
+                    scale: (vm::mod_(instr) >> 2) % 4,
+                    disp: 0,
+                };
+                asm.rm(true, &[], &[0x8D], dst, mem);
+            }
+            SuperscalarInstructionType::IMUL_R => asm.rr(true, &[], &[0x0F, 0xAF], dst, src),
+            SuperscalarInstructionType::IROR_C => asm.ri8(true, 0xC1, 1, dst, (imm & 63) as u8),
+            SuperscalarInstructionType::IADD_C7
+            | SuperscalarInstructionType::IADD_C8
+            | SuperscalarInstructionType::IADD_C9 => asm.ri32(true, 0x81, 0, dst, imm),
+            SuperscalarInstructionType::IXOR_C7
+            | SuperscalarInstructionType::IXOR_C8
+            | SuperscalarInstructionType::IXOR_C9 => asm.ri32(true, 0x81, 6, dst, imm),
+            SuperscalarInstructionType::IMULH_R | SuperscalarInstructionType::ISMULH_R => {
+                // mov rax, dst; mul src (imul src); mov dst, rdx
+                let ext = if SuperscalarInstructionType::from_opcode(vm::opcode(instr))
+                    == SuperscalarInstructionType::IMULH_R
+                {
+                    4
+                } else {
+                    5
+                };
+                asm.mov(RAX, dst);
+                asm.rr(true, &[], &[0xF7], ext, src);
+                asm.mov(dst, RDX);
+            }
+            SuperscalarInstructionType::IMUL_RCP => {
+                // mov rax, reciprocal; imul dst, rax
+                Self::mov_imm64(asm, RAX, reciprocals[imm as usize]);
+                asm.rr(true, &[], &[0x0F, 0xAF], dst, RAX);
+            }
+            SuperscalarInstructionType::COUNT | SuperscalarInstructionType::INVALID => {
+                unreachable!()
+            }
+        }
+    }
+}
@@ -1,12 +1,26 @@
-use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
-use aes::Aes128;
-use blake2::{Blake2b512, Digest};
-
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Blake2b512, Digest};
+
+use crate::aes_round::AesBackend;
+use crate::cache::Cache;
+use crate::parameters::RANDOMX_HASH_SIZE;
+use crate::vm::VMEnvironment;
+
+pub mod aes_round;
+pub mod cache;
+pub mod dataset;
+pub mod error;
+pub mod flags;
 pub mod helpers;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod memory;
 pub mod parameters;
 pub mod superscalar;
 pub mod vm;
 
+pub use crate::error::Error;
+
 /// Implement [AesGenerator1R](https://github.com/tevador/RandomX/blob/master/doc/specs.md#32-aesgenerator1r).
 ///
 /// TODO: implement it in assembly. It is pretty straightforward.
@@ -42,39 +56,26 @@ pub mod vm;
 ///   state0'          state1'          state2'          state3'
 /// ```
 pub fn aes_generator_1r(input: [u8; 64]) -> [u8; 64] {
-    // key0 - decrypt
-    let key0 = GenericArray::from(parameters::AES_GENERATOR_1R_K0);
-    let cipher = Aes128::new(&key0);
-    let state0: [u8; 16] = input[0..16].try_into().unwrap();
-    let mut state0 = GenericArray::from(state0);
-    cipher.decrypt_block(&mut state0);
-
-    // key1 - encrypt
-    let key1 = GenericArray::from(parameters::AES_GENERATOR_1R_K1);
-    let cipher = Aes128::new(&key1);
-    let state1: [u8; 16] = input[16..32].try_into().unwrap();
-    let mut state1 = GenericArray::from(state1);
-    cipher.encrypt_block(&mut state1);
+    aes_generator_1r_with(AesBackend::Software, input)
+}
 
-    // key2 - decrypt
-    let key2 = GenericArray::from(parameters::AES_GENERATOR_1R_K2);
-    let cipher = Aes128::new(&key2);
-    let state2: [u8; 16] = input[32..48].try_into().unwrap();
-    let mut state2 = GenericArray::from(state2);
-    cipher.decrypt_block(&mut state2);
+/// [aes_generator_1r] using the AES backend `aes`
+pub fn aes_generator_1r_with(aes: AesBackend, input: [u8; 64]) -> [u8; 64] {
+    let mut state0: [u8; 16] = input[0..16].try_into().unwrap();
+    let mut state1: [u8; 16] = input[16..32].try_into().unwrap();
+    let mut state2: [u8; 16] = input[32..48].try_into().unwrap();
+    let mut state3: [u8; 16] = input[48..64].try_into().unwrap();
 
-    // key3 - encrypt
-    let key3 = GenericArray::from(parameters::AES_GENERATOR_1R_K3);
-    let cipher = Aes128::new(&key3);
-    let state3: [u8; 16] = input[48..64].try_into().unwrap();
-    let mut state3 = GenericArray::from(state3);
-    cipher.encrypt_block(&mut state3);
+    aes.dec_round(&mut state0, &parameters::AES_GENERATOR_1R_K0);
+    aes.enc_round(&mut state1, &parameters::AES_GENERATOR_1R_K1);
+    aes.dec_round(&mut state2, &parameters::AES_GENERATOR_1R_K2);
+    aes.enc_round(&mut state3, &parameters::AES_GENERATOR_1R_K3);
 
     let mut output: [u8; 64] = [0; 64];
-    output[0..16].copy_from_slice(state0.as_slice());
-    output[16..32].copy_from_slice(state1.as_slice());
-    output[32..48].copy_from_slice(state2.as_slice());
-    output[48..64].copy_from_slice(state3.as_slice());
+    output[0..16].copy_from_slice(&state0);
+    output[16..32].copy_from_slice(&state1);
+    output[32..48].copy_from_slice(&state2);
+    output[48..64].copy_from_slice(&state3);
 
     output
 }
@@ -113,184 +114,162 @@ pub fn aes_generator_1r(input: [u8; 64]) -> [u8; 64] {
 ///   state0'          state1'          state2'          state3'
 /// ```
 pub fn aes_generator_4r(input: [u8; 64]) -> [u8; 64] {
-    // state0
-    let key0 = GenericArray::from(parameters::AES_GENERATOR_4R_K0);
-    let key1 = GenericArray::from(parameters::AES_GENERATOR_4R_K1);
-    let key2 = GenericArray::from(parameters::AES_GENERATOR_4R_K2);
-    let key3 = GenericArray::from(parameters::AES_GENERATOR_4R_K3);
-    let key4 = GenericArray::from(parameters::AES_GENERATOR_4R_K4);
-    let key5 = GenericArray::from(parameters::AES_GENERATOR_4R_K5);
-    let key6 = GenericArray::from(parameters::AES_GENERATOR_4R_K6);
-    let key7 = GenericArray::from(parameters::AES_GENERATOR_4R_K7);
-    let state0 = {
-        let cipher = Aes128::new(&key0);
-        let state: [u8; 16] = input[0..16].try_into().unwrap();
-        let mut state = GenericArray::from(state);
-        cipher.decrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key1);
-        cipher.decrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key2);
-        cipher.decrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key3);
-        cipher.decrypt_block(&mut state);
-        state
-    };
-
-    let state1 = {
-        let cipher = Aes128::new(&key0);
-        let state: [u8; 16] = input[16..32].try_into().unwrap();
-        let mut state = GenericArray::from(state);
-        cipher.encrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key1);
-        cipher.encrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key2);
-        cipher.encrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key3);
-        cipher.encrypt_block(&mut state);
-        state
-    };
-
-    let state2 = {
-        let cipher = Aes128::new(&key4);
-        let state: [u8; 16] = input[32..48].try_into().unwrap();
-        let mut state = GenericArray::from(state);
-        cipher.decrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key5);
-        cipher.decrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key6);
-        cipher.decrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key7);
-        cipher.decrypt_block(&mut state);
-        state
-    };
-
-    let state3 = {
-        let cipher = Aes128::new(&key4);
-        let state: [u8; 16] = input[48..64].try_into().unwrap();
-        let mut state = GenericArray::from(state);
-        cipher.encrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key5);
-        cipher.encrypt_block(&mut state);
-
-        let cipher = Aes128::new(&key6);
-        cipher.encrypt_block(&mut state);
+    aes_generator_4r_with(AesBackend::Software, input)
+}
 
-        let cipher = Aes128::new(&key7);
-        cipher.encrypt_block(&mut state);
-        state
-    };
+/// [aes_generator_4r] using the AES backend `aes`
+pub fn aes_generator_4r_with(aes: AesBackend, input: [u8; 64]) -> [u8; 64] {
+    let keys0 = [
+        parameters::AES_GENERATOR_4R_K0,
+        parameters::AES_GENERATOR_4R_K1,
+        parameters::AES_GENERATOR_4R_K2,
+        parameters::AES_GENERATOR_4R_K3,
+    ];
+    let keys1 = [
+        parameters::AES_GENERATOR_4R_K4,
+        parameters::AES_GENERATOR_4R_K5,
+        parameters::AES_GENERATOR_4R_K6,
+        parameters::AES_GENERATOR_4R_K7,
+    ];
+    let mut state0: [u8; 16] = input[0..16].try_into().unwrap();
+    let mut state1: [u8; 16] = input[16..32].try_into().unwrap();
+    let mut state2: [u8; 16] = input[32..48].try_into().unwrap();
+    let mut state3: [u8; 16] = input[48..64].try_into().unwrap();
+
+    for (key0, key1) in keys0.iter().zip(keys1.iter()) {
+        aes.dec_round(&mut state0, key0);
+        aes.enc_round(&mut state1, key0);
+        aes.dec_round(&mut state2, key1);
+        aes.enc_round(&mut state3, key1);
+    }
 
     let mut output: [u8; 64] = [0; 64];
-    output[0..16].copy_from_slice(state0.as_slice());
-    output[16..32].copy_from_slice(state1.as_slice());
-    output[32..48].copy_from_slice(state2.as_slice());
-    output[48..64].copy_from_slice(state3.as_slice());
+    output[0..16].copy_from_slice(&state0);
+    output[16..32].copy_from_slice(&state1);
+    output[32..48].copy_from_slice(&state2);
+    output[48..64].copy_from_slice(&state3);
 
     output
 }
 
-pub fn aes_hash1r(block: Vec<u8>) -> [u8; 64] {
-    // Let's suppose the block is 64bytes for now.
-    // I don't see how padding works.
-    assert_eq!(block.len(), 64);
-    let input: [u8; 64] = block.try_into().unwrap();
-
-    let mut state0 = GenericArray::from(parameters::AES_HASH1R_STATE0);
-    {
-        let key0: [u8; 16] = input[0..16].try_into().unwrap();
-        let key0 = GenericArray::from(key0);
-        let cipher = Aes128::new(&key0);
-        cipher.decrypt_block(&mut state0);
-    };
-
-    // key1 - encrypt
-    let mut state1 = GenericArray::from(parameters::AES_HASH1R_STATE1);
-    {
-        let key1: [u8; 16] = input[16..32].try_into().unwrap();
-        let key1 = GenericArray::from(key1);
-        let cipher = Aes128::new(&key1);
-        cipher.encrypt_block(&mut state1);
-    };
-
-    // key2 - decrypt
-    let mut state2 = GenericArray::from(parameters::AES_HASH1R_STATE2);
-    {
-        let key2: [u8; 16] = input[32..48].try_into().unwrap();
-        let key2 = GenericArray::from(key2);
-        let cipher = Aes128::new(&key2);
-        cipher.decrypt_block(&mut state2);
-    };
+/// Implement [AesHash1R](https://github.com/tevador/RandomX/blob/master/doc/specs.md#34-aeshash1r).
+///
+/// The input is processed in blocks of 64 bytes. Each block is split into
+/// four columns of 16 bytes which are used as round keys to encrypt (columns
+/// 0, 2) or decrypt (columns 1, 3) the internal state. Two extra rounds with
+/// the keys `xkey0` and `xkey1` are applied at the end to achieve full
+/// diffusion.
+///
+/// The length of the input must be a multiple of 64. See [AesHash1R] to hash
+/// an input provided in several parts.
+pub fn aes_hash1r(input: &[u8]) -> Result<[u8; 64], Error> {
+    aes_hash1r_with(AesBackend::Software, input)
+}
 
-    // key3 - encrypt
-    let mut state3 = GenericArray::from(parameters::AES_HASH1R_STATE3);
-    {
-        let key3: [u8; 16] = input[48..64].try_into().unwrap();
-        let key3 = GenericArray::from(key3);
-        let cipher = Aes128::new(&key3);
-        cipher.encrypt_block(&mut state3);
-    };
+/// [aes_hash1r] using the AES backend `aes`
+pub fn aes_hash1r_with(aes: AesBackend, input: &[u8]) -> Result<[u8; 64], Error> {
+    let mut hasher = AesHash1R::with_backend(aes);
+    hasher.update(input);
+    hasher.finalize()
+}
 
-    // TODO: process other blocks.
-    // Now the two final rounds. We are supposed to have state0, state1, state2
-    // and state3
+/// Incremental version of [aes_hash1r]. The input can be split anywhere, but
+/// its total length must be a multiple of 64.
+pub struct AesHash1R {
+    aes: AesBackend,
+    state: [[u8; 16]; 4],
+    // Bytes of an incomplete block, waiting for the next update
+    buffer: [u8; 64],
+    buffer_len: usize,
+    // Total length of the input
+    length: usize,
+}
 
-    // Compute finalState0
-    {
-        let key = GenericArray::from(parameters::AES_HASH1R_XKEY0);
-        let cipher = Aes128::new(&key);
-        cipher.encrypt_block(&mut state0);
-        let key = GenericArray::from(parameters::AES_HASH1R_XKEY1);
-        let cipher = Aes128::new(&key);
-        cipher.encrypt_block(&mut state0);
+impl Default for AesHash1R {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    // Compute finalState1
-    {
-        let key = GenericArray::from(parameters::AES_HASH1R_XKEY0);
-        let cipher = Aes128::new(&key);
-        cipher.decrypt_block(&mut state1);
-        let key = GenericArray::from(parameters::AES_HASH1R_XKEY1);
-        let cipher = Aes128::new(&key);
-        cipher.decrypt_block(&mut state1);
+impl AesHash1R {
+    /// Create a hasher using the software AES backend
+    pub fn new() -> Self {
+        Self::with_backend(AesBackend::Software)
     }
 
-    // Compute finalState2
-    {
-        let key = GenericArray::from(parameters::AES_HASH1R_XKEY0);
-        let cipher = Aes128::new(&key);
-        cipher.encrypt_block(&mut state2);
-        let key = GenericArray::from(parameters::AES_HASH1R_XKEY1);
-        let cipher = Aes128::new(&key);
-        cipher.encrypt_block(&mut state2);
+    /// Create a hasher using the AES backend `aes`
+    pub fn with_backend(aes: AesBackend) -> Self {
+        AesHash1R {
+            aes,
+            state: [
+                parameters::AES_HASH1R_STATE0,
+                parameters::AES_HASH1R_STATE1,
+                parameters::AES_HASH1R_STATE2,
+                parameters::AES_HASH1R_STATE3,
+            ],
+            buffer: [0; 64],
+            buffer_len: 0,
+            length: 0,
+        }
     }
 
-    // Compute finalState3
-    {
-        let key = GenericArray::from(parameters::AES_HASH1R_XKEY0);
-        let cipher = Aes128::new(&key);
-        cipher.decrypt_block(&mut state3);
-        let key = GenericArray::from(parameters::AES_HASH1R_XKEY1);
-        let cipher = Aes128::new(&key);
-        cipher.decrypt_block(&mut state3);
+    /// Absorb `input` into the state
+    pub fn update(&mut self, mut input: &[u8]) {
+        self.length += input.len();
+        if self.buffer_len > 0 {
+            let n = (64 - self.buffer_len).min(input.len());
+            self.buffer[self.buffer_len..self.buffer_len + n].copy_from_slice(&input[..n]);
+            self.buffer_len += n;
+            input = &input[n..];
+            if self.buffer_len < 64 {
+                return;
+            }
+            let block = self.buffer;
+            self.process_block(&block);
+            self.buffer_len = 0;
+        }
+        let mut blocks = input.chunks_exact(64);
+        for block in blocks.by_ref() {
+            self.process_block(block);
+        }
+        let remainder = blocks.remainder();
+        self.buffer[..remainder.len()].copy_from_slice(remainder);
+        self.buffer_len = remainder.len();
     }
 
-    let mut output: [u8; 64] = [0; 64];
-    output[0..16].copy_from_slice(state0.as_slice());
-    output[16..32].copy_from_slice(state1.as_slice());
-    output[32..48].copy_from_slice(state2.as_slice());
-    output[48..64].copy_from_slice(state3.as_slice());
+    /// Apply the two final rounds and return the hash. Fails if the total
+    /// length of the input is not a multiple of 64.
+    pub fn finalize(mut self) -> Result<[u8; 64], Error> {
+        if self.buffer_len != 0 {
+            return Err(Error::MisalignedInput {
+                length: self.length,
+            });
+        }
+        let aes = self.aes;
+        let [state0, state1, state2, state3] = &mut self.state;
+        // Two extra rounds to achieve full diffusion
+        for xkey in [parameters::AES_HASH1R_XKEY0, parameters::AES_HASH1R_XKEY1] {
+            aes.enc_round(state0, &xkey);
+            aes.dec_round(state1, &xkey);
+            aes.enc_round(state2, &xkey);
+            aes.dec_round(state3, &xkey);
+        }
 
-    output
+        let mut output: [u8; 64] = [0; 64];
+        for (chunk, state) in output.chunks_exact_mut(16).zip(self.state.iter()) {
+            chunk.copy_from_slice(state);
+        }
+        Ok(output)
+    }
+
+    fn process_block(&mut self, block: &[u8]) {
+        let aes = self.aes;
+        let [state0, state1, state2, state3] = &mut self.state;
+        aes.enc_round(state0, block[0..16].try_into().unwrap());
+        aes.dec_round(state1, block[16..32].try_into().unwrap());
+        aes.enc_round(state2, block[32..48].try_into().unwrap());
+        aes.dec_round(state3, block[48..64].try_into().unwrap());
+    }
 }
 
 pub struct BlakeGenerator {
@@ -303,17 +282,24 @@ pub struct BlakeGenerator {
 }
 
 impl BlakeGenerator {
+    /// Initialize the generator like `blake2_generator` in the reference
+    /// implementation.
+    ///
+    /// Only the first `BLAKE_GENERATOR_SEED_MAX_SIZE` (60) bytes of the seed
+    /// are used: longer seeds are silently truncated, as the reference
+    /// implementation does. This matters for the Cache, whose key can be of
+    /// any length: two keys sharing the same first 60 bytes generate the same
+    /// SuperscalarHash programs, but their Argon2d memory still differs.
     pub fn from_seed(seed: Vec<u8>, nonce: i32) -> Self {
-        // FIXME: the reference implementation do not raise any error here. It
-        // only truncates the seed to 60 bytes.
-        // Q: Any potential attack?
-        assert!(seed.len() <= parameters::BLAKE_GENERATOR_SEED_MAX_SIZE as usize);
+        let seed_size = seed
+            .len()
+            .min(parameters::BLAKE_GENERATOR_SEED_MAX_SIZE as usize);
 
         let mut data: [u8; 64] = [0; 64];
         // We initialize with only zeroes. The seed must be padded with zeroes if
         // its length is not 60.
         // Equivalent that copying into the first N bytes.
-        data[0..seed.len()].copy_from_slice(seed.as_slice());
+        data[0..seed_size].copy_from_slice(&seed[0..seed_size]);
         // FIXME: could be inlined for speed.
         data[60] = (nonce as u32) as u8;
         data[61] = ((nonce as u32) >> 8) as u8;
@@ -335,19 +321,50 @@ impl BlakeGenerator {
         self.data_index = 0;
     }
 
+    /// Return the next byte of the generator
     pub fn get_byte(&mut self) -> u8 {
         if self.data_index + 1 > 64 {
             self.update_state()
         }
-        self.data[self.data_index]
+        let res = self.data[self.data_index];
+        self.data_index += 1;
+        res
     }
 
+    /// Return the next 4 bytes of the generator as a little-endian integer.
+    /// Like the reference implementation, the remaining bytes are discarded
+    /// when less than 4 bytes are left before the state is updated.
     pub fn get_u32(&mut self) -> u32 {
         if self.data_index + 4 > 64 {
             self.update_state()
         }
         let mut b: [u8; 4] = [0; 4];
         b.copy_from_slice(&self.data[self.data_index..self.data_index + 4]);
-        u32::from_be_bytes(b)
+        self.data_index += 4;
+        u32::from_le_bytes(b)
     }
 }
+
+/// Compute the RandomX hash of `input` using `key`, as described in the
+/// [specification](https://github.com/tevador/RandomX/blob/master/doc/specs.md#41-hash-calculation).
+///
+/// The Cache is initialized from the key and the Dataset items are computed
+/// on the fly (light mode). Initializing the Cache is expensive (256 MiB of
+/// Argon2d), so this function is meant for occasional verification only.
+pub fn calculate_hash(key: &[u8], input: &[u8]) -> Result<[u8; RANDOMX_HASH_SIZE], Error> {
+    let cache = Cache::new(key)?;
+    Ok(VMEnvironment::new()?.calculate_hash(&cache, input))
+}
+
+/// Compute the commitment of `input` and of its RandomX hash `hash`, like
+/// `randomx_calculate_commitment` in the reference implementation: the
+/// Blake2b-256 hash of `input` followed by `hash`.
+pub fn calculate_commitment(
+    input: &[u8],
+    hash: &[u8; RANDOMX_HASH_SIZE],
+) -> [u8; RANDOMX_HASH_SIZE] {
+    let mut hasher = Blake2b::<U32>::new();
+    hasher.update(input);
+    hasher.update(hash);
+    hasher.finalize().into()
+}
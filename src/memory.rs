@@ -0,0 +1,256 @@
+//! Allocation of the large memory regions of RandomX: the Cache, the Dataset
+//! and the scratchpads. Like the `LargePageAllocator` of the reference
+//! implementation, the memory can be backed by huge pages, which reduces the
+//! TLB misses caused by the random accesses. See
+//! [RandomXFlags::LARGE_PAGES].
+//!
+//! Huge pages are only available on Linux. When they cannot be obtained, the
+//! allocation falls back to smaller pages, in this order: 1 GiB huge pages,
+//! 2 MiB huge pages, transparent huge pages, normal pages.
+//! [Memory::page_size] reports the pages which were obtained.
+use std::alloc::{self, Layout};
+use std::mem::{align_of, size_of};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::slice;
+
+use argon2::Block;
+
+use crate::flags::RandomXFlags;
+use crate::Error;
+
+/// Minimum alignment of the memory, the size of a cache line
+const ALIGNMENT: usize = 64;
+
+/// The pages backing a [Memory], from the smallest to the largest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    /// Normal pages, allocated by the global allocator
+    Normal,
+    /// Normal pages, which the kernel was asked with `madvise` to back with
+    /// transparent huge pages. The kernel does it on a best effort basis.
+    Transparent,
+    /// 2 MiB huge pages, mapped with `MAP_HUGETLB`
+    Huge2M,
+    /// 1 GiB huge pages, mapped with `MAP_HUGETLB`. They are only requested
+    /// for allocations of 1 GiB or more.
+    Huge1G,
+}
+
+impl PageSize {
+    /// Return the largest pages requested by `flags`: 2 MiB huge pages with
+    /// [RandomXFlags::LARGE_PAGES], normal pages otherwise.
+    pub fn from_flags(flags: RandomXFlags) -> Self {
+        if flags.contains(RandomXFlags::LARGE_PAGES) {
+            PageSize::Huge2M
+        } else {
+            PageSize::Normal
+        }
+    }
+}
+
+/// Types which can be stored in a [Memory]
+///
+/// # Safety
+///
+/// The value whose bytes are all zero must be a valid value of the type.
+pub unsafe trait Zeroable: Copy {}
+
+// SAFETY: integers and Argon2 blocks are plain arrays of integers
+unsafe impl Zeroable for u8 {}
+unsafe impl Zeroable for u64 {}
+unsafe impl Zeroable for Block {}
+
+/// `len` zero-initialized values of `T`, aligned to 64 bytes at least. The
+/// values are accessed through `Deref<Target = [T]>`.
+///
+/// The memory is only committed by the system when it is written.
+pub struct Memory<T: Zeroable> {
+    ptr: NonNull<T>,
+    len: usize,
+    /// Size in bytes of the allocation, rounded up to the page size for the
+    /// mappings
+    size: usize,
+    page_size: PageSize,
+}
+
+// SAFETY: the memory is owned, like in a Vec<T>
+unsafe impl<T: Zeroable + Send> Send for Memory<T> {}
+unsafe impl<T: Zeroable + Sync> Sync for Memory<T> {}
+
+impl<T: Zeroable> Memory<T> {
+    /// Allocate `len` zeroed values, backed by the largest pages available
+    /// up to `max_page_size`.
+    pub fn new(len: usize, max_page_size: PageSize) -> Result<Self, Error> {
+        let size = len
+            .checked_mul(size_of::<T>())
+            .ok_or(Error::AllocationFailed { size: usize::MAX })?;
+        #[cfg(target_os = "linux")]
+        for page_size in [PageSize::Huge1G, PageSize::Huge2M, PageSize::Transparent] {
+            if page_size > max_page_size {
+                continue;
+            }
+            if let Some((ptr, size)) = linux::map(size, page_size) {
+                return Ok(Memory {
+                    ptr: ptr.cast(),
+                    len,
+                    size,
+                    page_size,
+                });
+            }
+        }
+
+        let layout = Self::layout(size)?;
+        let ptr = if size == 0 {
+            // Dangling, but aligned like the other allocations
+            NonNull::new(layout.align() as *mut T).unwrap()
+        } else {
+            // SAFETY: the size of the layout is not zero
+            let ptr = unsafe { alloc::alloc_zeroed(layout) };
+            NonNull::new(ptr.cast()).ok_or(Error::AllocationFailed { size })?
+        };
+        Ok(Memory {
+            ptr,
+            len,
+            size,
+            page_size: PageSize::Normal,
+        })
+    }
+
+    /// Return the pages backing the memory
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    fn layout(size: usize) -> Result<Layout, Error> {
+        Layout::from_size_align(size, ALIGNMENT.max(align_of::<T>()))
+            .map_err(|_| Error::AllocationFailed { size })
+    }
+}
+
+impl<T: Zeroable> Deref for Memory<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // SAFETY: the memory holds `len` values, initialized to zero
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Zeroable> DerefMut for Memory<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // SAFETY: see deref
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Zeroable> Drop for Memory<T> {
+    fn drop(&mut self) {
+        match self.page_size {
+            PageSize::Normal if self.size == 0 => {}
+            // SAFETY: the memory was allocated by `new` with this layout
+            PageSize::Normal => unsafe {
+                alloc::dealloc(self.ptr.as_ptr().cast(), Self::layout(self.size).unwrap())
+            },
+            #[cfg(target_os = "linux")]
+            // SAFETY: the memory was mapped by `new` with this size
+            _ => unsafe { linux::unmap(self.ptr.cast(), self.size) },
+            #[cfg(not(target_os = "linux"))]
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs;
+    use std::ptr::{self, NonNull};
+
+    use super::PageSize;
+
+    const SIZE_2M: usize = 2 << 20;
+    const SIZE_1G: usize = 1 << 30;
+
+    /// Map `size` bytes of anonymous memory backed by `page_size` pages.
+    /// Return the address and the size of the mapping, rounded up to the
+    /// page size, or None if the pages are not available.
+    pub(super) fn map(size: usize, page_size: PageSize) -> Option<(NonNull<u8>, usize)> {
+        match page_size {
+            PageSize::Huge1G if size >= SIZE_1G => map_huge(size, SIZE_1G, libc::MAP_HUGE_1GB),
+            PageSize::Huge2M if size > 0 => map_huge(size, SIZE_2M, libc::MAP_HUGE_2MB),
+            PageSize::Transparent if size > 0 && transparent_huge_pages_enabled() => {
+                map_transparent(size)
+            }
+            _ => None,
+        }
+    }
+
+    /// Unmap memory returned by [map]
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `size` must have been returned by [map], and the memory
+    /// must not be used anymore.
+    pub(super) unsafe fn unmap(ptr: NonNull<u8>, size: usize) {
+        libc::munmap(ptr.as_ptr().cast(), size);
+    }
+
+    fn map_huge(size: usize, page: usize, flag: libc::c_int) -> Option<(NonNull<u8>, usize)> {
+        let size = size.checked_next_multiple_of(page)?;
+        let ptr = mmap(size, libc::MAP_HUGETLB | flag)?;
+        Some((ptr, size))
+    }
+
+    /// Map memory aligned to 2 MiB, so that the kernel can back all of it
+    /// with transparent huge pages, and advise it to do so
+    fn map_transparent(size: usize) -> Option<(NonNull<u8>, usize)> {
+        let size = size.checked_next_multiple_of(SIZE_2M)?;
+        // Map 2 MiB more than needed, then unmap the unaligned head and tail
+        let mapping = mmap(size.checked_add(SIZE_2M)?, 0)?.as_ptr();
+        let head = mapping.align_offset(SIZE_2M);
+        // SAFETY: the head and the tail are parts of the new mapping, which
+        // is not used yet
+        let ptr = unsafe {
+            let ptr = mapping.add(head);
+            if head > 0 {
+                libc::munmap(mapping.cast(), head);
+            }
+            libc::munmap(ptr.add(size).cast(), SIZE_2M - head);
+            ptr
+        };
+        // SAFETY: advice on the memory mapped above
+        if unsafe { libc::madvise(ptr.cast(), size, libc::MADV_HUGEPAGE) } != 0 {
+            // SAFETY: the memory is not used
+            unsafe { libc::munmap(ptr.cast(), size) };
+            return None;
+        }
+        Some((NonNull::new(ptr)?, size))
+    }
+
+    fn mmap(size: usize, flags: libc::c_int) -> Option<NonNull<u8>> {
+        // SAFETY: anonymous mapping, no existing memory is affected
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            None
+        } else {
+            NonNull::new(ptr.cast())
+        }
+    }
+
+    /// Return true unless transparent huge pages are disabled. The setting
+    /// is shown as, for example, `always [madvise] never`.
+    fn transparent_huge_pages_enabled() -> bool {
+        fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled")
+            .map(|setting| !setting.contains("[never]"))
+            .unwrap_or(false)
+    }
+}
@@ -18,6 +18,17 @@ pub const RANDOMX_ARGON_SALT: [u8; 8] = [b'R', b'a', b'n', b'd', b'o', b'm', b'X
 /// The number of random Cache accesses per Dataset item
 pub const RANDOMX_CACHE_ACCESSES: u64 = 8;
 
+/// Constants used to initialize the registers when computing a Dataset item,
+/// see [7.3](https://github.com/tevador/RandomX/blob/master/doc/specs.md#73-dataset-block-generation)
+pub const SUPERSCALAR_MUL0: u64 = 6364136223846793005;
+pub const SUPERSCALAR_ADD1: u64 = 9298411001130361340;
+pub const SUPERSCALAR_ADD2: u64 = 12065312585734608966;
+pub const SUPERSCALAR_ADD3: u64 = 9306329213124626780;
+pub const SUPERSCALAR_ADD4: u64 = 5281919268842080866;
+pub const SUPERSCALAR_ADD5: u64 = 10536153434571861004;
+pub const SUPERSCALAR_ADD6: u64 = 3398623926847679864;
+pub const SUPERSCALAR_ADD7: u64 = 9549104520008361294;
+
 /// Target latency for SuperscalarHash (in cycles of the reference CPU)
 pub const RANDOMX_SUPERSCALAR_LATENCY: u64 = 170;
 
@@ -25,8 +36,9 @@ pub const RANDOMX_SUPERSCALAR_MAX_SIZE: u64 = 3 * RANDOMX_SUPERSCALAR_LATENCY +
 
 pub const RANDOMX_CACHE_LINE_SIZE: u64 = RANDOMX_DATASET_INDEX_SIZE;
 
+/// Mask used to align the dataset addresses `ma` and `mx` to a cache line.
 pub const RANDOMX_CACHE_LINE_ASSIGN_MASK: u64 =
-    (RANDOMX_DATASET_BASE_SIZE - 1) & !(RANDOMX_CACHE_LINE_SIZE);
+    (RANDOMX_DATASET_BASE_SIZE - 1) & !(RANDOMX_CACHE_LINE_SIZE - 1);
 
 pub const RANDOMX_CACHE_SIZE: u64 = RANDOMX_ARGON_MEMORY * RANDOMX_ARGON_BLOCK_SIZE;
 
@@ -42,6 +54,10 @@ pub const RANDOMX_DATASET_INDEX_SIZE: u64 = 64;
 pub const RANDOMX_DATASET_EXTRA_ITEMS: u64 =
     RANDOMX_DATASET_EXTRA_SIZE / RANDOMX_DATASET_INDEX_SIZE;
 
+/// Total number of 64-byte items in the Dataset
+pub const RANDOMX_DATASET_ITEM_COUNT: u64 =
+    (RANDOMX_DATASET_BASE_SIZE + RANDOMX_DATASET_EXTRA_SIZE) / RANDOMX_DATASET_INDEX_SIZE;
+
 /// The number of instructions in a RandomX program
 pub const RANDOMX_PROGRAM_SIZE: u64 = 256;
 
@@ -66,6 +82,26 @@ pub const RANDOMX_SCRATCHPAD_L2: u64 = 262144;
 /// Scratchpad L1 size in bytes
 pub const RANDOMX_SCRATCHPAD_L1: u64 = 16384;
 
+/// Masks used to compute 8-byte aligned addresses in the different levels of
+/// the scratchpad
+pub const SCRATCHPAD_L1_MASK: u64 = (RANDOMX_SCRATCHPAD_L1 - 1) & !7;
+pub const SCRATCHPAD_L2_MASK: u64 = (RANDOMX_SCRATCHPAD_L2 - 1) & !7;
+pub const SCRATCHPAD_L3_MASK: u64 = (RANDOMX_SCRATCHPAD_L3 - 1) & !7;
+
+/// Mask used to compute 64-byte aligned addresses in the scratchpad
+pub const SCRATCHPAD_L3_MASK64: u64 = (RANDOMX_SCRATCHPAD_L3 - 1) & !63;
+
+/// Register which needs a displacement when used as the destination of
+/// `IADD_RS` (limitation of the x86 `lea` instruction)
+pub const REGISTER_NEEDS_DISPLACEMENT: usize = 5;
+
+/// `ISTORE` writes into L3 when `mod.cond` is at least this value
+pub const STORE_L3_CONDITION: u8 = 14;
+
+/// Mask applied to the destination register of `CBRANCH`, shifted by
+/// `mod.cond + RANDOMX_JUMP_OFFSET`
+pub const CONDITION_MASK: u64 = (1 << RANDOMX_JUMP_BITS) - 1;
+
 /// Keys used for
 /// [AesGenerator1R](https://github.com/tevador/RandomX/blob/master/doc/specs.md#32-aesgenerator1r)
 pub const AES_GENERATOR_1R_K0: [u8; 16] = [
@@ -198,3 +234,14 @@ pub const FLOAT_EXPONENT_MASK: u64 = (1 << FLOAT_EXPONENT_SIZE) - 1;
 pub const RANDOMX_CONST_EXPONENT_BITS: u64 = 0x300;
 pub const RANDOMX_STATIC_EXPONENT_BITS: u64 = 4;
 pub const RANDOMX_DYNAMIC_EXPONENT_BITS: u64 = 4;
+
+/// Mask applied to the values loaded into the e group registers before the
+/// exponent is set with `emask`
+pub const DYNAMIC_MANTISSA_MASK: u64 =
+    (1 << (FLOAT_MANTISSA_SIZE + RANDOMX_DYNAMIC_EXPONENT_BITS)) - 1;
+
+/// Mask applied by `FSCAL_R`: flips the sign and 4 bits of the exponent
+pub const FSCAL_MASK: u64 = 0x80F0000000000000;
+
+/// Size of the final hash in bytes
+pub const RANDOMX_HASH_SIZE: usize = 32;
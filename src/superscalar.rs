@@ -1,8 +1,12 @@
-use crate::parameters::RANDOMX_SUPERSCALAR_MAX_SIZE;
-use crate::vm::Instruction;
+use crate::helpers::{is_zero_or_power_of_2, mulh, reciprocal, sign_extend_2s_compl, smulh};
+use crate::parameters::{
+    RANDOMX_SUPERSCALAR_LATENCY, RANDOMX_SUPERSCALAR_MAX_SIZE, REGISTER_NEEDS_DISPLACEMENT,
+};
+use crate::vm::{dst, imm32, mod_, opcode, src, EncodedInstruction};
 use crate::BlakeGenerator;
 
 #[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SuperscalarInstructionType {
     ISUB_R = 0,    //1               p015                    1               3 (sub)
     IXOR_R = 1,    //1               p015                    1               3 (xor)
@@ -33,8 +37,32 @@ impl SuperscalarInstructionType {
                 | SuperscalarInstructionType::IMUL_RCP
         )
     }
+
+    /// Return the instruction type encoded in the opcode byte of a
+    /// superscalar instruction.
+    pub fn from_opcode(opcode: u8) -> Self {
+        match opcode {
+            0 => SuperscalarInstructionType::ISUB_R,
+            1 => SuperscalarInstructionType::IXOR_R,
+            2 => SuperscalarInstructionType::IADD_RS,
+            3 => SuperscalarInstructionType::IMUL_R,
+            4 => SuperscalarInstructionType::IROR_C,
+            5 => SuperscalarInstructionType::IADD_C7,
+            6 => SuperscalarInstructionType::IXOR_C7,
+            7 => SuperscalarInstructionType::IADD_C8,
+            8 => SuperscalarInstructionType::IXOR_C8,
+            9 => SuperscalarInstructionType::IADD_C9,
+            10 => SuperscalarInstructionType::IXOR_C9,
+            11 => SuperscalarInstructionType::IMULH_R,
+            12 => SuperscalarInstructionType::ISMULH_R,
+            13 => SuperscalarInstructionType::IMUL_RCP,
+            _ => SuperscalarInstructionType::INVALID,
+        }
+    }
 }
 
+/// uOPs (micro-ops) are represented only by the execution port they can go to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ExecutionPort {
     Null = 0,
     P0 = 1,
@@ -45,6 +73,564 @@ pub enum ExecutionPort {
     P015 = 1 | 2 | 4,
 }
 
+impl ExecutionPort {
+    fn accepts(self, port: ExecutionPort) -> bool {
+        (self as u8) & (port as u8) != 0
+    }
+}
+
+/// Macro-operation as output of the x86 decoder.
+/// Usually one macro-op is one x86 instruction, but 2 instructions are
+/// sometimes fused into 1 macro-op. A macro-op can consist of 1 or 2 uOPs.
+#[derive(Clone, Copy)]
+struct MacroOp {
+    size: u32,
+    latency: i32,
+    uop1: ExecutionPort,
+    uop2: ExecutionPort,
+    dependent: bool,
+}
+
+impl MacroOp {
+    const fn new(size: u32, latency: i32, uop1: ExecutionPort, uop2: ExecutionPort) -> Self {
+        MacroOp {
+            size,
+            latency,
+            uop1,
+            uop2,
+            dependent: false,
+        }
+    }
+
+    const fn dependent(self) -> Self {
+        MacroOp {
+            dependent: true,
+            ..self
+        }
+    }
+
+    fn is_simple(&self) -> bool {
+        self.uop2 == ExecutionPort::Null
+    }
+
+    /// Move instructions are eliminated and don't need an execution unit
+    fn is_eliminated(&self) -> bool {
+        self.uop1 == ExecutionPort::Null
+    }
+}
+
+// 3 bytes
+const SUB_RR: MacroOp = MacroOp::new(3, 1, ExecutionPort::P015, ExecutionPort::Null);
+const XOR_RR: MacroOp = MacroOp::new(3, 1, ExecutionPort::P015, ExecutionPort::Null);
+const IMUL_R: MacroOp = MacroOp::new(3, 4, ExecutionPort::P1, ExecutionPort::P5);
+const MUL_R: MacroOp = MacroOp::new(3, 4, ExecutionPort::P1, ExecutionPort::P5);
+const MOV_RR: MacroOp = MacroOp::new(3, 0, ExecutionPort::Null, ExecutionPort::Null);
+
+// 4 bytes
+const LEA_SIB: MacroOp = MacroOp::new(4, 1, ExecutionPort::P01, ExecutionPort::Null);
+const IMUL_RR: MacroOp = MacroOp::new(4, 3, ExecutionPort::P1, ExecutionPort::Null);
+const ROR_RI: MacroOp = MacroOp::new(4, 1, ExecutionPort::P05, ExecutionPort::Null);
+
+// 7 bytes (can be optionally padded with nop to 8 or 9 bytes)
+const ADD_RI: MacroOp = MacroOp::new(7, 1, ExecutionPort::P015, ExecutionPort::Null);
+const XOR_RI: MacroOp = MacroOp::new(7, 1, ExecutionPort::P015, ExecutionPort::Null);
+
+// 10 bytes
+const MOV_RI64: MacroOp = MacroOp::new(10, 1, ExecutionPort::P015, ExecutionPort::Null);
+
+/// Description of a superscalar instruction in terms of macro-ops.
+/// `result_op`, `dst_op` and `src_op` are the indices of the macro-ops which
+/// respectively write the result, need the destination and need the source
+/// register.
+struct SuperscalarInstructionInfo {
+    instruction_type: SuperscalarInstructionType,
+    ops: &'static [MacroOp],
+    result_op: usize,
+    dst_op: usize,
+    src_op: Option<usize>,
+}
+
+impl SuperscalarInstructionInfo {
+    const fn single(
+        instruction_type: SuperscalarInstructionType,
+        ops: &'static [MacroOp],
+        src_op: Option<usize>,
+    ) -> Self {
+        SuperscalarInstructionInfo {
+            instruction_type,
+            ops,
+            result_op: 0,
+            dst_op: 0,
+            src_op,
+        }
+    }
+}
+
+const INFO_ISUB_R: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::ISUB_R, &[SUB_RR], Some(0));
+const INFO_IXOR_R: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::IXOR_R, &[XOR_RR], Some(0));
+const INFO_IADD_RS: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::IADD_RS, &[LEA_SIB], Some(0));
+const INFO_IMUL_R: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::IMUL_R, &[IMUL_RR], Some(0));
+const INFO_IROR_C: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::IROR_C, &[ROR_RI], None);
+const INFO_IADD_C7: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::IADD_C7, &[ADD_RI], None);
+const INFO_IXOR_C7: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::IXOR_C7, &[XOR_RI], None);
+const INFO_IADD_C8: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::IADD_C8, &[ADD_RI], None);
+const INFO_IXOR_C8: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::IXOR_C8, &[XOR_RI], None);
+const INFO_IADD_C9: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::IADD_C9, &[ADD_RI], None);
+const INFO_IXOR_C9: SuperscalarInstructionInfo =
+    SuperscalarInstructionInfo::single(SuperscalarInstructionType::IXOR_C9, &[XOR_RI], None);
+const INFO_IMULH_R: SuperscalarInstructionInfo = SuperscalarInstructionInfo {
+    instruction_type: SuperscalarInstructionType::IMULH_R,
+    ops: &[MOV_RR, MUL_R, MOV_RR],
+    result_op: 1,
+    dst_op: 0,
+    src_op: Some(1),
+};
+const INFO_ISMULH_R: SuperscalarInstructionInfo = SuperscalarInstructionInfo {
+    instruction_type: SuperscalarInstructionType::ISMULH_R,
+    ops: &[MOV_RR, IMUL_R, MOV_RR],
+    result_op: 1,
+    dst_op: 0,
+    src_op: Some(1),
+};
+const INFO_IMUL_RCP: SuperscalarInstructionInfo = SuperscalarInstructionInfo {
+    instruction_type: SuperscalarInstructionType::IMUL_RCP,
+    ops: &[MOV_RI64, IMUL_RR.dependent()],
+    result_op: 1,
+    dst_op: 1,
+    src_op: None,
+};
+const INFO_NOP: SuperscalarInstructionInfo = SuperscalarInstructionInfo {
+    instruction_type: SuperscalarInstructionType::INVALID,
+    ops: &[],
+    result_op: 0,
+    dst_op: 0,
+    src_op: None,
+};
+
+const SLOT_3: [&SuperscalarInstructionInfo; 2] = [&INFO_ISUB_R, &INFO_IXOR_R];
+const SLOT_3L: [&SuperscalarInstructionInfo; 4] =
+    [&INFO_ISUB_R, &INFO_IXOR_R, &INFO_IMULH_R, &INFO_ISMULH_R];
+const SLOT_4: [&SuperscalarInstructionInfo; 2] = [&INFO_IROR_C, &INFO_IADD_RS];
+const SLOT_7: [&SuperscalarInstructionInfo; 2] = [&INFO_IXOR_C7, &INFO_IADD_C7];
+const SLOT_8: [&SuperscalarInstructionInfo; 2] = [&INFO_IXOR_C8, &INFO_IADD_C8];
+const SLOT_9: [&SuperscalarInstructionInfo; 2] = [&INFO_IXOR_C9, &INFO_IADD_C9];
+const SLOT_10: &SuperscalarInstructionInfo = &INFO_IMUL_RCP;
+
+/// One of the options to split a 16-byte decode window into 3 or 4 x86
+/// instructions. RandomX uses instructions with a native size of 3 (sub, xor,
+/// mul, mov), 4 (lea, mul), 7 (xor, add immediate) or 10 bytes (mov 64-bit
+/// immediate). Slots with sizes of 8 or 9 bytes need to be padded with a nop
+/// instruction.
+struct DecoderBuffer {
+    index: i32,
+    counts: &'static [u32],
+}
+
+const DECODE_BUFFER_484: DecoderBuffer = DecoderBuffer {
+    index: 0,
+    counts: &[4, 8, 4],
+};
+const DECODE_BUFFER_7333: DecoderBuffer = DecoderBuffer {
+    index: 1,
+    counts: &[7, 3, 3, 3],
+};
+const DECODE_BUFFER_3733: DecoderBuffer = DecoderBuffer {
+    index: 2,
+    counts: &[3, 7, 3, 3],
+};
+const DECODE_BUFFER_493: DecoderBuffer = DecoderBuffer {
+    index: 3,
+    counts: &[4, 9, 3],
+};
+const DECODE_BUFFER_4444: DecoderBuffer = DecoderBuffer {
+    index: 4,
+    counts: &[4, 4, 4, 4],
+};
+const DECODE_BUFFER_3310: DecoderBuffer = DecoderBuffer {
+    index: 5,
+    counts: &[3, 3, 10],
+};
+
+const DECODE_BUFFERS: [&DecoderBuffer; 4] = [
+    &DECODE_BUFFER_484,
+    &DECODE_BUFFER_7333,
+    &DECODE_BUFFER_3733,
+    &DECODE_BUFFER_493,
+];
+
+impl DecoderBuffer {
+    fn fetch_next(
+        instruction_type: SuperscalarInstructionType,
+        cycle: i32,
+        mul_count: i32,
+        gen: &mut BlakeGenerator,
+    ) -> &'static DecoderBuffer {
+        // If the current RandomX instruction is "IMULH", the next fetch
+        // configuration must be 3-3-10 because the full 128-bit
+        // multiplication instruction is 3 bytes long and decodes to 2 uOPs on
+        // Intel CPUs. Intel CPUs can decode at most 4 uOPs per cycle, so this
+        // requires a 2-1-1 configuration for a total of 3 macro ops.
+        if instruction_type == SuperscalarInstructionType::IMULH_R
+            || instruction_type == SuperscalarInstructionType::ISMULH_R
+        {
+            return &DECODE_BUFFER_3310;
+        }
+
+        // To make sure that the multiplication port is saturated, a 4-4-4-4
+        // configuration is generated if the number of multiplications is
+        // lower than the number of cycles.
+        if mul_count < cycle + 1 {
+            return &DECODE_BUFFER_4444;
+        }
+
+        // If the current RandomX instruction is "IMUL_RCP", the next buffer
+        // must begin with a 4-byte slot for multiplication.
+        if instruction_type == SuperscalarInstructionType::IMUL_RCP {
+            return if gen.get_byte() & 1 == 1 {
+                &DECODE_BUFFER_484
+            } else {
+                &DECODE_BUFFER_493
+            };
+        }
+
+        // Default: select a random fetch configuration.
+        DECODE_BUFFERS[(gen.get_byte() & 3) as usize]
+    }
+}
+
+#[derive(Clone, Copy)]
+struct RegisterInfo {
+    latency: i32,
+    last_op_group: SuperscalarInstructionType,
+    last_op_par: i32,
+}
+
+impl Default for RegisterInfo {
+    fn default() -> Self {
+        RegisterInfo {
+            latency: 0,
+            last_op_group: SuperscalarInstructionType::INVALID,
+            last_op_par: -1,
+        }
+    }
+}
+
+fn select_register(available_registers: &[usize], gen: &mut BlakeGenerator) -> Option<usize> {
+    match available_registers.len() {
+        0 => None,
+        1 => Some(available_registers[0]),
+        n => Some(available_registers[gen.get_u32() as usize % n]),
+    }
+}
+
+/// Superscalar instruction being built by the generator. It consists of one
+/// or more macro-ops.
+struct SuperscalarInstruction {
+    info: &'static SuperscalarInstructionInfo,
+    src: i32,
+    dst: i32,
+    mod_: u8,
+    imm32: u32,
+    op_group: SuperscalarInstructionType,
+    op_group_par: i32,
+    can_reuse: bool,
+    group_par_is_source: bool,
+}
+
+impl SuperscalarInstruction {
+    fn null() -> Self {
+        SuperscalarInstruction {
+            info: &INFO_NOP,
+            src: -1,
+            dst: -1,
+            mod_: 0,
+            imm32: 0,
+            op_group: SuperscalarInstructionType::INVALID,
+            op_group_par: -1,
+            can_reuse: false,
+            group_par_is_source: false,
+        }
+    }
+
+    fn instruction_type(&self) -> SuperscalarInstructionType {
+        self.info.instruction_type
+    }
+
+    /// Translate into the RandomX instruction format
+    fn to_instruction(&self) -> EncodedInstruction {
+        let src = if self.src >= 0 { self.src } else { self.dst };
+        ((self.imm32 as u64) << 32)
+            | ((self.mod_ as u64) << 24)
+            | ((src as u8 as u64) << 16)
+            | ((self.dst as u8 as u64) << 8)
+            | (self.instruction_type() as u8 as u64)
+    }
+
+    fn create_for_slot(
+        &mut self,
+        gen: &mut BlakeGenerator,
+        slot_size: u32,
+        fetch_type: i32,
+        is_last: bool,
+    ) {
+        match slot_size {
+            3 => {
+                // If this is the last slot, we can also select 3-byte
+                // multiplication instructions
+                if is_last {
+                    self.create(SLOT_3L[(gen.get_byte() & 3) as usize], gen);
+                } else {
+                    self.create(SLOT_3[(gen.get_byte() & 1) as usize], gen);
+                }
+            }
+            4 => {
+                // If this is the 4-4-4-4 buffer, issue multiplications as the
+                // first 3 instructions
+                if fetch_type == 4 && !is_last {
+                    self.create(&INFO_IMUL_R, gen);
+                } else {
+                    self.create(SLOT_4[(gen.get_byte() & 1) as usize], gen);
+                }
+            }
+            7 => self.create(SLOT_7[(gen.get_byte() & 1) as usize], gen),
+            8 => self.create(SLOT_8[(gen.get_byte() & 1) as usize], gen),
+            9 => self.create(SLOT_9[(gen.get_byte() & 1) as usize], gen),
+            10 => self.create(SLOT_10, gen),
+            _ => unreachable!(),
+        }
+    }
+
+    fn create(&mut self, info: &'static SuperscalarInstructionInfo, gen: &mut BlakeGenerator) {
+        self.info = info;
+        self.src = -1;
+        self.dst = -1;
+        self.can_reuse = false;
+        self.group_par_is_source = false;
+        match info.instruction_type {
+            SuperscalarInstructionType::ISUB_R => {
+                self.mod_ = 0;
+                self.imm32 = 0;
+                self.op_group = SuperscalarInstructionType::IADD_RS;
+                self.group_par_is_source = true;
+            }
+            SuperscalarInstructionType::IXOR_R => {
+                self.mod_ = 0;
+                self.imm32 = 0;
+                self.op_group = SuperscalarInstructionType::IXOR_R;
+                self.group_par_is_source = true;
+            }
+            SuperscalarInstructionType::IADD_RS => {
+                self.mod_ = gen.get_byte();
+                self.imm32 = 0;
+                self.op_group = SuperscalarInstructionType::IADD_RS;
+                self.group_par_is_source = true;
+            }
+            SuperscalarInstructionType::IMUL_R => {
+                self.mod_ = 0;
+                self.imm32 = 0;
+                self.op_group = SuperscalarInstructionType::IMUL_R;
+                self.group_par_is_source = true;
+            }
+            SuperscalarInstructionType::IROR_C => {
+                self.mod_ = 0;
+                self.imm32 = 0;
+                while self.imm32 == 0 {
+                    self.imm32 = (gen.get_byte() & 63) as u32;
+                }
+                self.op_group = SuperscalarInstructionType::IROR_C;
+                self.op_group_par = -1;
+            }
+            SuperscalarInstructionType::IADD_C7
+            | SuperscalarInstructionType::IADD_C8
+            | SuperscalarInstructionType::IADD_C9 => {
+                self.mod_ = 0;
+                self.imm32 = gen.get_u32();
+                self.op_group = SuperscalarInstructionType::IADD_C7;
+                self.op_group_par = -1;
+            }
+            SuperscalarInstructionType::IXOR_C7
+            | SuperscalarInstructionType::IXOR_C8
+            | SuperscalarInstructionType::IXOR_C9 => {
+                self.mod_ = 0;
+                self.imm32 = gen.get_u32();
+                self.op_group = SuperscalarInstructionType::IXOR_C7;
+                self.op_group_par = -1;
+            }
+            SuperscalarInstructionType::IMULH_R => {
+                self.can_reuse = true;
+                self.mod_ = 0;
+                self.imm32 = 0;
+                self.op_group = SuperscalarInstructionType::IMULH_R;
+                self.op_group_par = gen.get_u32() as i32;
+            }
+            SuperscalarInstructionType::ISMULH_R => {
+                self.can_reuse = true;
+                self.mod_ = 0;
+                self.imm32 = 0;
+                self.op_group = SuperscalarInstructionType::ISMULH_R;
+                self.op_group_par = gen.get_u32() as i32;
+            }
+            SuperscalarInstructionType::IMUL_RCP => {
+                self.mod_ = 0;
+                self.imm32 = gen.get_u32();
+                while is_zero_or_power_of_2(self.imm32 as u64) {
+                    self.imm32 = gen.get_u32();
+                }
+                self.op_group = SuperscalarInstructionType::IMUL_RCP;
+                self.op_group_par = -1;
+            }
+            _ => {}
+        }
+    }
+
+    /// Conditions for the destination register:
+    /// - value must be ready at the required cycle
+    /// - cannot be the same as the source register unless the instruction
+    ///   allows it. This avoids optimizable instructions such as `xor r, r`
+    ///   or `sub r, r`
+    /// - register cannot be multiplied twice in a row unless
+    ///   `allow_chained_mul` is true. This avoids accumulation of trailing
+    ///   zeroes in registers due to excessive multiplication.
+    /// - either the last instruction applied to the register or its source
+    ///   must be different than this instruction. This avoids optimizable
+    ///   instruction sequences such as `xor r1, r2; xor r1, r2`.
+    /// - register r5 cannot be the destination of the IADD_RS instruction
+    ///   (limitation of the x86 lea instruction)
+    fn select_destination(
+        &mut self,
+        cycle: i32,
+        allow_chained_mul: bool,
+        registers: &[RegisterInfo; 8],
+        gen: &mut BlakeGenerator,
+    ) -> bool {
+        let available_registers: Vec<usize> = (0..8)
+            .filter(|&i| {
+                let r = &registers[i];
+                r.latency <= cycle
+                    && (self.can_reuse || i as i32 != self.src)
+                    && (allow_chained_mul
+                        || self.op_group != SuperscalarInstructionType::IMUL_R
+                        || r.last_op_group != SuperscalarInstructionType::IMUL_R)
+                    && (r.last_op_group != self.op_group || r.last_op_par != self.op_group_par)
+                    && (self.instruction_type() != SuperscalarInstructionType::IADD_RS
+                        || i != REGISTER_NEEDS_DISPLACEMENT)
+            })
+            .collect();
+        match select_register(&available_registers, gen) {
+            Some(reg) => {
+                self.dst = reg as i32;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn select_source(
+        &mut self,
+        cycle: i32,
+        registers: &[RegisterInfo; 8],
+        gen: &mut BlakeGenerator,
+    ) -> bool {
+        let available_registers: Vec<usize> =
+            (0..8).filter(|&i| registers[i].latency <= cycle).collect();
+        // If there are only 2 available registers for IADD_RS and one of
+        // them is r5, select it as the source because it cannot be the
+        // destination
+        if available_registers.len() == 2
+            && self.instruction_type() == SuperscalarInstructionType::IADD_RS
+            && (available_registers[0] == REGISTER_NEEDS_DISPLACEMENT
+                || available_registers[1] == REGISTER_NEEDS_DISPLACEMENT)
+        {
+            self.src = REGISTER_NEEDS_DISPLACEMENT as i32;
+            self.op_group_par = self.src;
+            return true;
+        }
+        match select_register(&available_registers, gen) {
+            Some(reg) => {
+                self.src = reg as i32;
+                if self.group_par_is_source {
+                    self.op_group_par = self.src;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+const CYCLE_MAP_SIZE: usize = RANDOMX_SUPERSCALAR_LATENCY as usize + 4;
+const LOOK_FORWARD_CYCLES: i32 = 4;
+const MAX_THROWAWAY_COUNT: i32 = 256;
+
+type PortBusy = [[bool; 3]; CYCLE_MAP_SIZE];
+
+/// The scheduling is done optimistically by checking port availability in
+/// order P5 -> P0 -> P1 to not overload port P1 (multiplication) by
+/// instructions that can go to any port.
+fn schedule_uop(uop: ExecutionPort, port_busy: &mut PortBusy, cycle: i32, commit: bool) -> i32 {
+    let start = cycle.max(0) as usize;
+    for (cycle, busy) in port_busy.iter_mut().enumerate().skip(start) {
+        for (port, index) in [
+            (ExecutionPort::P5, 2),
+            (ExecutionPort::P0, 0),
+            (ExecutionPort::P1, 1),
+        ] {
+            if uop.accepts(port) && !busy[index] {
+                if commit {
+                    busy[index] = true;
+                }
+                return cycle as i32;
+            }
+        }
+    }
+    -1
+}
+
+fn schedule_mop(
+    mop: &MacroOp,
+    port_busy: &mut PortBusy,
+    mut cycle: i32,
+    dep_cycle: i32,
+    commit: bool,
+) -> i32 {
+    // If this macro-op depends on the previous one, increase the starting
+    // cycle if needed. This handles an explicit dependency chain in IMUL_RCP.
+    if mop.dependent {
+        cycle = cycle.max(dep_cycle);
+    }
+    if mop.is_eliminated() {
+        return cycle;
+    }
+    if mop.is_simple() {
+        return schedule_uop(mop.uop1, port_busy, cycle, commit);
+    }
+    // Macro-ops with 2 uOPs are scheduled conservatively by requiring both
+    // uOPs to execute in the same cycle
+    while (cycle as usize) < CYCLE_MAP_SIZE {
+        let cycle1 = schedule_uop(mop.uop1, port_busy, cycle, false);
+        let cycle2 = schedule_uop(mop.uop2, port_busy, cycle, false);
+        if cycle1 >= 0 && cycle1 == cycle2 {
+            if commit {
+                schedule_uop(mop.uop1, port_busy, cycle1, true);
+                schedule_uop(mop.uop2, port_busy, cycle2, true);
+            }
+            return cycle1;
+        }
+        cycle += 1;
+    }
+    -1
+}
+
 // FIXME: check types
 pub struct SuperscalarProgram {
     pub size: u32,
@@ -59,23 +645,287 @@ pub struct SuperscalarProgram {
     pub asic_latencies: [u32; 8],
     // FIXME
     pub ipc: f32,
-    pub program_buffer: [Instruction; RANDOMX_SUPERSCALAR_MAX_SIZE as usize],
+    pub program_buffer: [EncodedInstruction; RANDOMX_SUPERSCALAR_MAX_SIZE as usize],
 }
 
 impl SuperscalarProgram {
-    pub fn generate(_gen: &mut BlakeGenerator) -> Self {
-        // let mut current_instruction = Instruction::
-        let mut _macro_op_index = 0;
-        let mut _code_size = 0;
-        let mut _macro_op_count = 0;
-        let mut _cycle = 0;
-        let mut _dep_cycle = 0;
-        let mut _retire_cycle = 0;
-        let mut _ports_saturated: bool = false;
-        let mut _program_size = 0;
-        let mut _mul_count = 0;
-        let mut _decode_cycle = 0;
-        let mut _throw_away_count = 0;
-        unimplemented!()
+    /// Generate a random SuperscalarHash program as described in
+    /// [6.3](https://github.com/tevador/RandomX/blob/master/doc/specs.md#63-program-generation).
+    /// The generator simulates the decoding and the scheduling of the
+    /// instructions on the reference CPU until the target latency
+    /// `RANDOMX_SUPERSCALAR_LATENCY` is reached or the execution ports are
+    /// saturated.
+    pub fn generate(gen: &mut BlakeGenerator) -> Self {
+        let mut port_busy: PortBusy = [[false; 3]; CYCLE_MAP_SIZE];
+        let mut registers = [RegisterInfo::default(); 8];
+        let mut program_buffer = [0; RANDOMX_SUPERSCALAR_MAX_SIZE as usize];
+
+        let mut current_instruction = SuperscalarInstruction::null();
+        let mut macro_op_index: usize = 0;
+        let mut code_size: u32 = 0;
+        let mut macro_op_count: u32 = 0;
+        let mut cycle: i32 = 0;
+        let mut dep_cycle: i32 = 0;
+        let mut retire_cycle: i32 = 0;
+        let mut ports_saturated: bool = false;
+        let mut program_size: usize = 0;
+        let mut mul_count: i32 = 0;
+        let mut decode_cycle: i32 = 0;
+        let mut throw_away_count: i32 = 0;
+
+        // Decode instructions for RANDOMX_SUPERSCALAR_LATENCY cycles or until
+        // an execution port is saturated. Each decode cycle decodes 16 bytes
+        // of x86 code. Since a decode cycle produces on average 3.45 macro-ops
+        // and there are only 3 ALU ports, execution ports are always
+        // saturated first. The cycle limit is present only to guarantee loop
+        // termination.
+        while decode_cycle < RANDOMX_SUPERSCALAR_LATENCY as i32
+            && !ports_saturated
+            && program_size < RANDOMX_SUPERSCALAR_MAX_SIZE as usize
+        {
+            // Select a decode configuration
+            let decode_buffer = DecoderBuffer::fetch_next(
+                current_instruction.instruction_type(),
+                decode_cycle,
+                mul_count,
+                gen,
+            );
+
+            let mut buffer_index: usize = 0;
+
+            // Fill all instruction slots in the current decode buffer
+            while buffer_index < decode_buffer.counts.len() {
+                let top_cycle = cycle;
+
+                // If we have issued all macro-ops for the current RandomX
+                // instruction, create a new instruction
+                if macro_op_index >= current_instruction.info.ops.len() {
+                    if ports_saturated || program_size >= RANDOMX_SUPERSCALAR_MAX_SIZE as usize {
+                        break;
+                    }
+                    // Select an instruction so that the first macro-op fits
+                    // into the current slot
+                    current_instruction.create_for_slot(
+                        gen,
+                        decode_buffer.counts[buffer_index],
+                        decode_buffer.index,
+                        decode_buffer.counts.len() == buffer_index + 1,
+                    );
+                    macro_op_index = 0;
+                }
+                let mop = current_instruction.info.ops[macro_op_index];
+
+                // Calculate the earliest cycle when this macro-op (all of its
+                // uOPs) can be scheduled for execution
+                let mut schedule_cycle =
+                    schedule_mop(&mop, &mut port_busy, cycle, dep_cycle, false);
+                if schedule_cycle < 0 {
+                    ports_saturated = true;
+                    break;
+                }
+
+                // Find a source register (if applicable) that will be ready
+                // when this instruction executes
+                if Some(macro_op_index) == current_instruction.info.src_op {
+                    let mut forward = 0;
+                    // If no suitable operand is ready, look up to
+                    // LOOK_FORWARD_CYCLES forward
+                    while forward < LOOK_FORWARD_CYCLES
+                        && !current_instruction.select_source(schedule_cycle, &registers, gen)
+                    {
+                        schedule_cycle += 1;
+                        cycle += 1;
+                        forward += 1;
+                    }
+                    // If no register was found, throw the instruction away
+                    // and try another one
+                    if forward == LOOK_FORWARD_CYCLES {
+                        if throw_away_count < MAX_THROWAWAY_COUNT {
+                            throw_away_count += 1;
+                            macro_op_index = current_instruction.info.ops.len();
+                            continue;
+                        }
+                        // Abort this decode buffer
+                        current_instruction = SuperscalarInstruction::null();
+                        break;
+                    }
+                }
+
+                // Find a destination register that will be ready when this
+                // instruction executes
+                if macro_op_index == current_instruction.info.dst_op {
+                    let mut forward = 0;
+                    while forward < LOOK_FORWARD_CYCLES
+                        && !current_instruction.select_destination(
+                            schedule_cycle,
+                            throw_away_count > 0,
+                            &registers,
+                            gen,
+                        )
+                    {
+                        schedule_cycle += 1;
+                        cycle += 1;
+                        forward += 1;
+                    }
+                    // Throw the instruction away
+                    if forward == LOOK_FORWARD_CYCLES {
+                        if throw_away_count < MAX_THROWAWAY_COUNT {
+                            throw_away_count += 1;
+                            macro_op_index = current_instruction.info.ops.len();
+                            continue;
+                        }
+                        // Abort this decode buffer
+                        current_instruction = SuperscalarInstruction::null();
+                        break;
+                    }
+                }
+                throw_away_count = 0;
+
+                // Recalculate when the instruction can be scheduled for
+                // execution based on operand availability
+                schedule_cycle =
+                    schedule_mop(&mop, &mut port_busy, schedule_cycle, schedule_cycle, true);
+                if schedule_cycle < 0 {
+                    ports_saturated = true;
+                    break;
+                }
+
+                // Calculate when the result will be ready
+                dep_cycle = schedule_cycle + mop.latency;
+
+                // If this instruction writes the result, modify register
+                // information:
+                // - latency: which cycle the register will be ready
+                // - last_op_group: the last operation that was applied to the
+                //   register
+                // - last_op_par: the last operation source value (-1 =
+                //   constant, 0-7 = register)
+                if macro_op_index == current_instruction.info.result_op {
+                    let ri = &mut registers[current_instruction.dst as usize];
+                    retire_cycle = dep_cycle;
+                    ri.latency = retire_cycle;
+                    ri.last_op_group = current_instruction.op_group;
+                    ri.last_op_par = current_instruction.op_group_par;
+                }
+                code_size += mop.size;
+                buffer_index += 1;
+                macro_op_index += 1;
+                macro_op_count += 1;
+
+                // Terminating condition
+                if schedule_cycle >= RANDOMX_SUPERSCALAR_LATENCY as i32 {
+                    ports_saturated = true;
+                }
+                cycle = top_cycle;
+
+                // When all macro-ops of the current instruction have been
+                // issued, add the instruction into the program
+                if macro_op_index >= current_instruction.info.ops.len() {
+                    program_buffer[program_size] = current_instruction.to_instruction();
+                    program_size += 1;
+                    if current_instruction.instruction_type().is_multiplication() {
+                        mul_count += 1;
+                    }
+                }
+            }
+            cycle += 1;
+            decode_cycle += 1;
+        }
+
+        let ipc = macro_op_count as f32 / retire_cycle as f32;
+
+        // Calculate ASIC latency: assumes 1 cycle latency for all operations
+        // and unlimited parallelization.
+        let mut asic_latencies = [0u32; 8];
+        for instr in program_buffer.iter().take(program_size) {
+            let dst = dst(*instr) as usize;
+            let src = src(*instr) as usize;
+            let lat_dst = asic_latencies[dst] + 1;
+            let lat_src = if dst != src {
+                asic_latencies[src] + 1
+            } else {
+                0
+            };
+            asic_latencies[dst] = lat_dst.max(lat_src);
+        }
+
+        // The address register is the register with the highest ASIC latency
+        let mut asic_latency_max = 0;
+        let mut addr_reg = 0;
+        let mut cpu_latencies = [0u32; 8];
+        for i in 0..8 {
+            if asic_latencies[i] > asic_latency_max {
+                asic_latency_max = asic_latencies[i];
+                addr_reg = i as u32;
+            }
+            cpu_latencies[i] = registers[i].latency as u32;
+        }
+
+        SuperscalarProgram {
+            size: program_size as u32,
+            addr_reg,
+            code_size,
+            macro_ops: macro_op_count,
+            decode_cycles: decode_cycle as u32,
+            cpu_latency: retire_cycle as u32,
+            asic_latency: asic_latency_max,
+            mul_count: mul_count as u32,
+            cpu_latencies,
+            asic_latencies,
+            ipc,
+            program_buffer,
+        }
+    }
+
+    /// Return the instructions of the program
+    pub fn instructions(&self) -> &[EncodedInstruction] {
+        &self.program_buffer[0..self.size as usize]
+    }
+}
+
+/// Execute a SuperscalarHash program on the registers `r`.
+///
+/// The `IMUL_RCP` instructions of the programs stored in a Cache have their
+/// immediate replaced by an index into `reciprocals`. When `reciprocals` is
+/// `None`, the reciprocal is computed from the immediate.
+pub fn execute_superscalar(
+    r: &mut [u64; 8],
+    prog: &SuperscalarProgram,
+    reciprocals: Option<&[u64]>,
+) {
+    for instr in prog.instructions() {
+        let dst = dst(*instr) as usize;
+        let src = src(*instr) as usize;
+        let imm = imm32(*instr);
+        match SuperscalarInstructionType::from_opcode(opcode(*instr)) {
+            SuperscalarInstructionType::ISUB_R => r[dst] = r[dst].wrapping_sub(r[src]),
+            SuperscalarInstructionType::IXOR_R => r[dst] ^= r[src],
+            SuperscalarInstructionType::IADD_RS => {
+                let shift = (mod_(*instr) >> 2) % 4;
+                r[dst] = r[dst].wrapping_add(r[src] << shift)
+            }
+            SuperscalarInstructionType::IMUL_R => r[dst] = r[dst].wrapping_mul(r[src]),
+            SuperscalarInstructionType::IROR_C => r[dst] = r[dst].rotate_right(imm),
+            SuperscalarInstructionType::IADD_C7
+            | SuperscalarInstructionType::IADD_C8
+            | SuperscalarInstructionType::IADD_C9 => {
+                r[dst] = r[dst].wrapping_add(sign_extend_2s_compl(imm))
+            }
+            SuperscalarInstructionType::IXOR_C7
+            | SuperscalarInstructionType::IXOR_C8
+            | SuperscalarInstructionType::IXOR_C9 => r[dst] ^= sign_extend_2s_compl(imm),
+            SuperscalarInstructionType::IMULH_R => r[dst] = mulh(r[dst], r[src]),
+            SuperscalarInstructionType::ISMULH_R => r[dst] = smulh(r[dst], r[src]),
+            SuperscalarInstructionType::IMUL_RCP => {
+                let rcp = match reciprocals {
+                    Some(reciprocals) => reciprocals[imm as usize],
+                    None => reciprocal(imm as u64),
+                };
+                r[dst] = r[dst].wrapping_mul(rcp)
+            }
+            SuperscalarInstructionType::COUNT | SuperscalarInstructionType::INVALID => {
+                unreachable!()
+            }
+        }
     }
 }
@@ -1,11 +1,38 @@
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Blake2b512, Digest};
+
 use crate::{
-    helpers::{f64_from_u64, float_mask},
+    aes_generator_1r_with, aes_generator_4r_with, aes_hash1r_with,
+    aes_round::AesBackend,
+    cache::Cache,
+    dataset::Dataset,
+    flags::RandomXFlags,
+    helpers::{
+        f64_from_u64, fadd, fdiv, float_mask, fmul, fsqrt, fsub, is_zero_or_power_of_2, mulh,
+        reciprocal, sign_extend_2s_compl, smulh, ROUNDING_MODE_NEAREST,
+    },
+    memory::{Memory, PageSize},
     parameters::{
-        RANDOMX_CACHE_LINE_SIZE, RANDOMX_DATASET_EXTRA_ITEMS, RANDOMX_PROGRAM_ITERATIONS,
-        RANDOMX_PROGRAM_SIZE, RANDOMX_SCRATCHPAD_L3,
+        CONDITION_MASK, DYNAMIC_MANTISSA_MASK, FSCAL_MASK, RANDOMX_CACHE_LINE_ASSIGN_MASK,
+        RANDOMX_CACHE_LINE_SIZE, RANDOMX_DATASET_EXTRA_ITEMS, RANDOMX_DATASET_ITEM_COUNT,
+        RANDOMX_FREQ_CBRANCH, RANDOMX_FREQ_CFROUND, RANDOMX_FREQ_FADD_M, RANDOMX_FREQ_FADD_R,
+        RANDOMX_FREQ_FDIV_M, RANDOMX_FREQ_FMUL_R, RANDOMX_FREQ_FSCAL_R, RANDOMX_FREQ_FSQRT_R,
+        RANDOMX_FREQ_FSUB_M, RANDOMX_FREQ_FSUB_R, RANDOMX_FREQ_FSWAP_R, RANDOMX_FREQ_IADD_M,
+        RANDOMX_FREQ_IADD_RS, RANDOMX_FREQ_IMULH_M, RANDOMX_FREQ_IMULH_R, RANDOMX_FREQ_IMUL_M,
+        RANDOMX_FREQ_IMUL_R, RANDOMX_FREQ_IMUL_RCP, RANDOMX_FREQ_INEG_R, RANDOMX_FREQ_IROL_R,
+        RANDOMX_FREQ_IROR_R, RANDOMX_FREQ_ISMULH_M, RANDOMX_FREQ_ISMULH_R, RANDOMX_FREQ_ISTORE,
+        RANDOMX_FREQ_ISUB_M, RANDOMX_FREQ_ISUB_R, RANDOMX_FREQ_ISWAP_R, RANDOMX_FREQ_IXOR_M,
+        RANDOMX_FREQ_IXOR_R, RANDOMX_FREQ_NOP, RANDOMX_HASH_SIZE, RANDOMX_JUMP_OFFSET,
+        RANDOMX_PROGRAM_COUNT, RANDOMX_PROGRAM_ITERATIONS, RANDOMX_PROGRAM_SIZE,
+        RANDOMX_SCRATCHPAD_L3, REGISTER_NEEDS_DISPLACEMENT, SCRATCHPAD_L1_MASK, SCRATCHPAD_L2_MASK,
+        SCRATCHPAD_L3_MASK, SCRATCHPAD_L3_MASK64, STORE_L3_CONDITION,
     },
+    AesHash1R, Error,
 };
 
+#[cfg(feature = "jit")]
+use crate::jit::JitCompiler;
+
 /// Each instruction word is 64 bits long
 /// 63         32       24      16       8         0
 /// ------------------------------------------------
@@ -45,8 +72,51 @@ pub fn opcode(i: EncodedInstruction) -> u8 {
     i as u8
 }
 
-// FIXME: enforce alignment
+/// The registers of the VM, laid out like the `RegisterFile` of the
+/// reference implementation: r0-r7, then f0-f3, e0-e3 and a0-a3. The
+/// floating point registers are pairs of values, the low half first, as in
+/// an xmm register. The structure is aligned on a cache line.
+#[repr(C, align(64))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RegisterFile {
+    pub r: [u64; 8],
+    pub f: [[f64; 2]; 4],
+    pub e: [[f64; 2]; 4],
+    // We do use only u64 instead of f64 because we only do care about the
+    // underlying bytes
+    pub a: [[u64; 2]; 4],
+}
+
+impl RegisterFile {
+    /// Serialize the registers in the order of the fields, each value in
+    /// little-endian. This is the memory of the structure on x86-64, which
+    /// the reference implementation hashes.
+    pub fn to_bytes(&self) -> [u8; 256] {
+        let mut res = [0u8; 256];
+        let mut words = res.chunks_exact_mut(8);
+        let mut put = |word: u64| words.next().unwrap().copy_from_slice(&word.to_le_bytes());
+        for r in self.r {
+            put(r);
+        }
+        for [lo, hi] in self.f.into_iter().chain(self.e) {
+            put(lo.to_bits());
+            put(hi.to_bits());
+        }
+        for [lo, hi] in self.a {
+            put(lo);
+            put(hi);
+        }
+        res
+    }
+}
+
+/// The configuration of the current program, laid out like the
+/// `ProgramConfiguration` of the reference implementation and aligned on a
+/// cache line
+#[repr(C, align(64))]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct ProgramConfiguration {
+    /// The exponent masks of the e group registers, low half first
     pub emask: [u64; 2],
     pub read_reg0: u32,
     pub read_reg1: u32,
@@ -56,16 +126,8 @@ pub struct ProgramConfiguration {
 
 // The VMEnvironment tries to replicate the structure defined in virtual_machine.hpp
 pub struct VMEnvironment {
-    // Note: must be aligned as in virtual_machine.hpp
     pub program_buffer: Vec<EncodedInstruction>,
-    // this is called RegisterFile in the reference implementation
-    pub r_registers: [u64; 8],
-    pub f_registers: [f64; 4],
-    pub e_registers: [f64; 4],
-    // We do use only u64 instead of f64 because we only do care about the
-    // underlying bytes
-    pub a_registers: [[u64; 2]; 4],
-    // ProgramConfiguration
+    pub registers: RegisterFile,
     pub configuration: ProgramConfiguration,
     // The two following fields are stored in MemoryRegisters
     /// Contains the memory address of the next Dataset read
@@ -75,116 +137,557 @@ pub struct VMEnvironment {
     // FIXME: there is additional pointer in MemoryRegisters.
     // FIXME: union randomx_cache randomx_dataset
     pub dataset_offset: u64,
-    pub fprc: [bool; 2],
+    /// Floating point rounding mode, see the `ROUNDING_MODE_*` constants in
+    /// [crate::helpers].
+    pub fprc: u32,
 
-    // FIXME: correct type?
+    /// Number of remaining iterations of the current program
     pub ic: u32,
     pub sp_addr0: u32,
     pub sp_addr1: u32,
-    pub scratchpad: Vec<u8>,
+    /// The scratchpad, aligned on a cache line like all the [Memory]
+    pub scratchpad: Memory<u8>,
+    /// The AES implementation used by the generators and the scratchpad
+    /// fingerprint
+    pub aes: AesBackend,
+    /// When set, the programs are compiled to machine code instead of being
+    /// run by the interpreter. If a program cannot be written to the
+    /// executable memory, the compiler is dropped and the interpreter is
+    /// used from then on.
+    #[cfg(feature = "jit")]
+    pub jit: Option<JitCompiler>,
+    // The program compiled by `load_program`
+    pub(crate) bytecode: Vec<ByteCode>,
+}
+
+/// The memory the Dataset items are read from
+#[derive(Clone, Copy)]
+pub enum DatasetMemory<'a> {
+    /// Light mode: the items are computed from the Cache when they are read
+    Light(&'a Cache),
+    /// Fast mode: the items are read from the precomputed Dataset
+    Fast(&'a Dataset),
+}
+
+impl<'a> DatasetMemory<'a> {
+    /// Return the Dataset item `item_number` as 8 registers. Fails with
+    /// [Error::InvalidItemRange] if there is no such item.
+    pub fn item(&self, item_number: u64) -> Result<[u64; 8], Error> {
+        if item_number < RANDOMX_DATASET_ITEM_COUNT {
+            Ok(self.item_in_range(item_number))
+        } else {
+            Err(Error::InvalidItemRange {
+                start_item: item_number,
+                count: 1,
+            })
+        }
+    }
+
+    /// [DatasetMemory::item], for the item numbers computed by the VM, which
+    /// are always in range
+    fn item_in_range(&self, item_number: u64) -> [u64; 8] {
+        match self {
+            DatasetMemory::Light(cache) => cache.init_dataset_item(item_number),
+            DatasetMemory::Fast(dataset) => {
+                let mut item = [0u64; 8];
+                for (r, bytes) in item
+                    .iter_mut()
+                    .zip(dataset.item_in_range(item_number).chunks_exact(8))
+                {
+                    *r = u64::from_le_bytes(bytes.try_into().unwrap());
+                }
+                item
+            }
+        }
+    }
+}
+
+impl<'a> From<&'a Cache> for DatasetMemory<'a> {
+    fn from(cache: &'a Cache) -> Self {
+        DatasetMemory::Light(cache)
+    }
+}
+
+impl<'a> From<&'a Dataset> for DatasetMemory<'a> {
+    fn from(dataset: &'a Dataset) -> Self {
+        DatasetMemory::Fast(dataset)
+    }
 }
 
-impl Default for VMEnvironment {
+/// A RandomX virtual machine, equivalent to the `randomx_vm` of the reference
+/// implementation. Depending on [RandomXFlags::FULL_MEM], the Dataset items
+/// are read from a precomputed Dataset (fast mode) or computed from the Cache
+/// (light mode). Both modes produce the same hashes.
+///
+/// With [RandomXFlags::JIT], the programs are compiled to machine code. This
+/// requires the `jit` feature. With [RandomXFlags::SECURE] too, the memory
+/// holding the code is never writable and executable at the same time. With
+/// [RandomXFlags::LARGE_PAGES], the scratchpad is backed by huge pages when
+/// they are available. The flags which are not implemented are rejected, see
+/// [Vm::UNSUPPORTED_FLAGS].
+///
+/// The memory of the VM, including the scratchpad, is allocated once by
+/// [Vm::new]. The state is reset in place for each input: once the buffers
+/// have reached their size, which happens with the first hash, computing a
+/// hash does not allocate.
+///
+/// Like in the reference implementation, the hashes of several inputs can be
+/// pipelined with [Vm::calculate_hash_first], [Vm::calculate_hash_next] and
+/// [Vm::calculate_hash_last], or with [Vm::hash_batch].
+pub struct Vm<'a> {
+    pub flags: RandomXFlags,
+    memory: DatasetMemory<'a>,
+    env: VMEnvironment,
+    // Seed of the first program of the hash in progress, between
+    // `calculate_hash_first` and `calculate_hash_last`
+    pipeline_seed: Option<[u8; 64]>,
+}
+
+impl<'a> Vm<'a> {
+    /// The flags which are not implemented yet
+    #[cfg(feature = "jit")]
+    pub const UNSUPPORTED_FLAGS: RandomXFlags = RandomXFlags::DEFAULT;
+    /// The flags which are not implemented yet
+    #[cfg(not(feature = "jit"))]
+    pub const UNSUPPORTED_FLAGS: RandomXFlags = RandomXFlags::JIT.union(RandomXFlags::SECURE);
+
+    /// Create a virtual machine. Like `randomx_create_vm`, the Cache is
+    /// required in light mode and the Dataset is required in fast mode. The
+    /// Dataset must be initialized, see [Dataset::is_initialized].
+    pub fn new(
+        flags: RandomXFlags,
+        cache: Option<&'a Cache>,
+        dataset: Option<&'a Dataset>,
+    ) -> Result<Self, Error> {
+        let unsupported = flags.intersection(Self::UNSUPPORTED_FLAGS);
+        if unsupported != RandomXFlags::DEFAULT {
+            return Err(Error::UnsupportedFlag(unsupported));
+        }
+        let memory = if flags.contains(RandomXFlags::FULL_MEM) {
+            match dataset {
+                Some(dataset) if dataset.is_initialized() => DatasetMemory::Fast(dataset),
+                _ => return Err(Error::DatasetNotInitialized),
+            }
+        } else {
+            DatasetMemory::Light(cache.ok_or(Error::CacheNotInitialized)?)
+        };
+        let scratchpad = Memory::new(RANDOMX_SCRATCHPAD_L3 as usize, PageSize::from_flags(flags))?;
+        let env = VMEnvironment {
+            aes: AesBackend::from_flags(flags)?,
+            #[cfg(feature = "jit")]
+            jit: if flags.contains(RandomXFlags::JIT) {
+                Some(JitCompiler::new(flags.contains(RandomXFlags::SECURE))?)
+            } else {
+                None
+            },
+            ..VMEnvironment::with_scratchpad(scratchpad)
+        };
+        Ok(Vm {
+            flags,
+            memory,
+            env,
+            pipeline_seed: None,
+        })
+    }
+
+    /// Compute the RandomX hash of `input`, reusing the memory of the VM. A
+    /// pipeline of hashes in progress is abandoned.
+    pub fn hash(&mut self, input: &[u8]) -> [u8; RANDOMX_HASH_SIZE] {
+        self.pipeline_seed = None;
+        self.env.calculate_hash(self.memory, input)
+    }
+
+    /// Same as [Vm::hash], named like `randomx_calculate_hash`
+    pub fn calculate_hash(&mut self, input: &[u8]) -> [u8; RANDOMX_HASH_SIZE] {
+        self.hash(input)
+    }
+
+    /// Start a pipeline of hashes with `input`, like
+    /// `randomx_calculate_hash_first`. Only the scratchpad is initialized:
+    /// the hash is returned by the next call to [Vm::calculate_hash_next] or
+    /// [Vm::calculate_hash_last].
+    pub fn calculate_hash_first(&mut self, input: &[u8]) {
+        self.pipeline_seed = Some(self.start_pipeline(input));
+    }
+
+    /// Return the hash of the previous input of the pipeline and start the
+    /// hash of `next_input`, like `randomx_calculate_hash_next`. The
+    /// scratchpad is initialized for `next_input` while its fingerprint is
+    /// computed.
+    ///
+    /// Fails with [Error::NoHashInProgress] if no pipeline was started with
+    /// [Vm::calculate_hash_first].
+    pub fn calculate_hash_next(
+        &mut self,
+        next_input: &[u8],
+    ) -> Result<[u8; RANDOMX_HASH_SIZE], Error> {
+        let seed = self.pipeline_seed.take().ok_or(Error::NoHashInProgress)?;
+        let (hash, next_seed) = self.continue_pipeline(seed, next_input);
+        self.pipeline_seed = Some(next_seed);
+        Ok(hash)
+    }
+
+    /// Return the hash of the last input of the pipeline, like
+    /// `randomx_calculate_hash_last`, and end the pipeline.
+    ///
+    /// Fails with [Error::NoHashInProgress] if no pipeline was started with
+    /// [Vm::calculate_hash_first].
+    pub fn calculate_hash_last(&mut self) -> Result<[u8; RANDOMX_HASH_SIZE], Error> {
+        let seed = self.pipeline_seed.take().ok_or(Error::NoHashInProgress)?;
+        Ok(self.end_pipeline(seed))
+    }
+
+    /// Compute the hashes of `inputs`, in order, with a pipeline. The
+    /// results are the same as with [Vm::hash]. A pipeline of
+    /// hashes in progress is abandoned.
+    pub fn hash_batch<I: AsRef<[u8]>>(&mut self, inputs: &[I]) -> Vec<[u8; RANDOMX_HASH_SIZE]> {
+        self.pipeline_seed = None;
+        let Some((first, rest)) = inputs.split_first() else {
+            return Vec::new();
+        };
+        let mut hashes = Vec::with_capacity(inputs.len());
+        let mut seed = self.start_pipeline(first.as_ref());
+        for input in rest {
+            let (hash, next_seed) = self.continue_pipeline(seed, input.as_ref());
+            hashes.push(hash);
+            seed = next_seed;
+        }
+        hashes.push(self.end_pipeline(seed));
+        hashes
+    }
+
+    /// Initialize the scratchpad for `input` and return the seed of its
+    /// first program
+    fn start_pipeline(&mut self, input: &[u8]) -> [u8; 64] {
+        let mut seed: [u8; 64] = Blake2b512::digest(input).into();
+        self.env.init_scratchpad(&mut seed);
+        seed
+    }
+
+    /// Finish the hash started with `seed` and start the hash of
+    /// `next_input`. Return the hash and the seed of the next input.
+    fn continue_pipeline(
+        &mut self,
+        seed: [u8; 64],
+        next_input: &[u8],
+    ) -> ([u8; RANDOMX_HASH_SIZE], [u8; 64]) {
+        self.env.execute_programs(self.memory, seed);
+        let mut next_seed: [u8; 64] = Blake2b512::digest(next_input).into();
+        let hash = self.env.finalize_hash_and_fill(&mut next_seed);
+        (hash, next_seed)
+    }
+
+    /// Finish the hash started with `seed`
+    fn end_pipeline(&mut self, seed: [u8; 64]) -> [u8; RANDOMX_HASH_SIZE] {
+        self.env.execute_programs(self.memory, seed);
+        self.env.finalize_hash()
+    }
+
+    /// Return the pages backing the scratchpad. With
+    /// [RandomXFlags::LARGE_PAGES], they are huge pages if the system could
+    /// provide them.
+    pub fn scratchpad_page_size(&self) -> PageSize {
+        self.env.scratchpad.page_size()
+    }
+}
+
+impl VMEnvironment {
     /// Initialize a new VMEnvironment.
     /// It also performs the initialization described in
     /// [4.6.1](https://github.com/tevador/RandomX/blob/master/doc/specs.md#461-initialization).
-    fn default() -> VMEnvironment {
+    ///
+    /// Fails with [Error::AllocationFailed] if the scratchpad cannot be
+    /// allocated.
+    pub fn new() -> Result<Self, Error> {
+        let scratchpad = Memory::new(RANDOMX_SCRATCHPAD_L3 as usize, PageSize::Normal)?;
+        Ok(Self::with_scratchpad(scratchpad))
+    }
+
+    /// Initialize a new VMEnvironment using `scratchpad`, which must hold
+    /// `RANDOMX_SCRATCHPAD_L3` bytes
+    fn with_scratchpad(scratchpad: Memory<u8>) -> Self {
         let program_buffer: Vec<EncodedInstruction> =
             Vec::with_capacity(RANDOMX_PROGRAM_SIZE as usize);
-        let scratchpad: Vec<u8> = Vec::with_capacity(RANDOMX_SCRATCHPAD_L3 as usize);
         let ma = 0;
         let mx = 0;
-        let configuration = ProgramConfiguration {
-            emask: [0; 2],
-            read_reg0: 0,
-            read_reg1: 0,
-            read_reg2: 0,
-            read_reg3: 0,
-        };
-        let r_registers = [0; 8];
         VMEnvironment {
             program_buffer,
-            r_registers,
-            f_registers: [0.0; 4],
-            e_registers: [0.0; 4],
-            a_registers: [[0; 2]; 4],
+            registers: RegisterFile::default(),
             ma,
             mx,
             dataset_offset: 0,
             // FIXME: additional fields, see doc
-            fprc: [false; 2],
+            fprc: ROUNDING_MODE_NEAREST,
             ic: RANDOMX_PROGRAM_ITERATIONS,
             sp_addr0: mx,
             sp_addr1: ma,
-            // FIXME:
-            configuration,
+            configuration: ProgramConfiguration::default(),
             scratchpad,
+            aes: AesBackend::Software,
+            #[cfg(feature = "jit")]
+            jit: None,
+            bytecode: Vec::with_capacity(RANDOMX_PROGRAM_SIZE as usize),
         }
     }
-}
 
-impl VMEnvironment {
-    /// Load the program into the program buffer of the environment
-    pub fn load_program(_env: &mut Self, _filename: String) {
-        //
+    /// Initialize the scratchpad as described in
+    /// [4.2](https://github.com/tevador/RandomX/blob/master/doc/specs.md#42-scratchpad-initialization):
+    /// the scratchpad is filled with the output of AesGenerator1R seeded with
+    /// `seed`. The final state of the generator is written back into `seed`
+    /// and is used to generate the first program.
+    pub fn init_scratchpad(&mut self, seed: &mut [u8; 64]) {
+        for chunk in self.scratchpad.chunks_exact_mut(64) {
+            *seed = aes_generator_1r_with(self.aes, *seed);
+            chunk.copy_from_slice(seed);
+        }
+    }
+
+    /// Load a program into the program buffer of the environment.
+    /// AesGenerator4R, seeded with `seed`, produces 128 bytes of
+    /// configuration followed by `RANDOMX_PROGRAM_SIZE` instructions, see
+    /// [4.5](https://github.com/tevador/RandomX/blob/master/doc/specs.md#45-vm-programming).
+    pub fn load_program(&mut self, seed: [u8; 64]) {
+        let mut state = seed;
+        let mut config: [u64; 16] = [0; 16];
+        for i in 0..2 {
+            state = aes_generator_4r_with(self.aes, state);
+            for (j, word) in state.chunks_exact(8).enumerate() {
+                config[8 * i + j] = u64::from_le_bytes(word.try_into().unwrap());
+            }
+        }
+        self.program_buffer.clear();
+        while self.program_buffer.len() < RANDOMX_PROGRAM_SIZE as usize {
+            state = aes_generator_4r_with(self.aes, state);
+            for word in state.chunks_exact(8) {
+                self.program_buffer
+                    .push(u64::from_le_bytes(word.try_into().unwrap()));
+            }
+        }
+        self.configure(config);
+        compile_program(&self.program_buffer, &mut self.bytecode);
+    }
+
+    /// Replace the program of the environment by `program`, keeping the
+    /// current configuration. The program can then be executed with
+    /// [interpreter].
+    pub fn set_program(&mut self, program: &[EncodedInstruction]) {
+        self.program_buffer.clear();
+        self.program_buffer.extend_from_slice(program);
+        compile_program(&self.program_buffer, &mut self.bytecode);
     }
 
     /// Build a virtual machine environment based on the given configuration.
     /// It follows the [section 4.5 - VM
     /// programming](https://github.com/tevador/RandomX/blob/master/doc/specs.md#45-vm-programming).
-    pub fn from_configuration(config: [u64; 16]) -> Self {
-        let a0_l: u64 = f64_from_u64(config[0]);
-        let a0_h: u64 = f64_from_u64(config[1]);
-        let a1_l: u64 = f64_from_u64(config[2]);
-        let a1_h: u64 = f64_from_u64(config[3]);
-        let a2_l: u64 = f64_from_u64(config[4]);
-        let a2_h: u64 = f64_from_u64(config[5]);
-        let a3_l: u64 = f64_from_u64(config[6]);
-        let a3_h: u64 = f64_from_u64(config[7]);
-        // FIXME: check this
-        // FIXME: check u32 converstion
-        let ma: u32 = (config[8] & ((1 << 32) - 1)).try_into().unwrap();
-        // FIXME: check u32 converstion
-        let mx: u32 = (config[10] & ((1 << 32) - 1)).try_into().unwrap();
-        // FIXME: we could have a boolean for the read reg0?
-        let read_reg0: u32 = (config[12] & 1).try_into().unwrap();
-        let read_reg1: u32 = (config[12] & 2).try_into().unwrap();
-        let read_reg2: u32 = (config[12] & 4).try_into().unwrap();
-        let read_reg3: u32 = (config[12] & 8).try_into().unwrap();
-        let dataset_offset: u64 =
+    ///
+    /// Fails with [Error::AllocationFailed] if the scratchpad cannot be
+    /// allocated.
+    pub fn from_configuration(config: [u64; 16]) -> Result<Self, Error> {
+        let mut env = Self::new()?;
+        env.configure(config);
+        Ok(env)
+    }
+
+    /// Apply the 128 bytes of configuration data produced with the program,
+    /// as described in
+    /// [4.5](https://github.com/tevador/RandomX/blob/master/doc/specs.md#45-vm-programming).
+    fn configure(&mut self, config: [u64; 16]) {
+        for i in 0..4 {
+            let lo: u64 = f64_from_u64(config[2 * i]);
+            let hi: u64 = f64_from_u64(config[2 * i + 1]);
+            self.registers.a[i] = [lo, hi];
+        }
+        self.ma = (config[8] & RANDOMX_CACHE_LINE_ASSIGN_MASK) as u32;
+        self.mx = config[10] as u32;
+        let address_registers = config[12];
+        self.configuration.read_reg0 = (address_registers & 1) as u32;
+        self.configuration.read_reg1 = 2 + ((address_registers >> 1) & 1) as u32;
+        self.configuration.read_reg2 = 4 + ((address_registers >> 2) & 1) as u32;
+        self.configuration.read_reg3 = 6 + ((address_registers >> 3) & 1) as u32;
+        self.dataset_offset =
             (config[13] % (RANDOMX_DATASET_EXTRA_ITEMS + 1)) * RANDOMX_CACHE_LINE_SIZE;
-        let configuration = ProgramConfiguration {
-            emask: [float_mask(config[14]), float_mask(config[15])],
-            read_reg0,
-            read_reg1,
-            read_reg2,
-            read_reg3,
-        };
-        let program_buffer: Vec<EncodedInstruction> =
-            Vec::with_capacity(RANDOMX_PROGRAM_SIZE as usize);
-        let scratchpad: Vec<u8> = Vec::with_capacity(RANDOMX_SCRATCHPAD_L3 as usize);
-        Self {
-            program_buffer,
-            r_registers: [0; 8],
-            f_registers: [0.0; 4],
-            e_registers: [0.0; 4],
-            a_registers: [[a0_h, a0_l], [a1_h, a1_l], [a2_h, a2_l], [a3_h, a3_l]],
-            configuration,
-            ma,
-            mx,
-            // FIXME: there is additional pointer in MemoryRegisters.
-            // FIXME: union randomx_cache randomx_dataset
-            dataset_offset,
-            fprc: [false; 2],
-            ic: RANDOMX_PROGRAM_ITERATIONS,
-            sp_addr0: mx,
-            sp_addr1: ma,
-            scratchpad,
+        self.configuration.emask = [float_mask(config[14]), float_mask(config[15])];
+        self.ic = RANDOMX_PROGRAM_ITERATIONS;
+        self.sp_addr0 = self.mx;
+        self.sp_addr1 = self.ma;
+        self.registers.r = [0; 8];
+    }
+
+    /// Execute the `ic` remaining iterations of the loaded program, as
+    /// described in
+    /// [4.6.2](https://github.com/tevador/RandomX/blob/master/doc/specs.md#462-loop-execution).
+    /// The Dataset items are read from `memory`. A program loaded with
+    /// [VMEnvironment::load_program] can be executed in several calls by
+    /// lowering `ic`.
+    pub fn run<'a>(&mut self, memory: impl Into<DatasetMemory<'a>>) {
+        let memory = memory.into();
+        #[cfg(feature = "jit")]
+        if let Some(mut jit) = self.jit.take() {
+            if jit.run(self, memory).is_ok() {
+                self.jit = Some(jit);
+                return;
+            }
+        }
+        while self.ic > 0 {
+            let sp_mix = self.registers.r[self.configuration.read_reg0 as usize]
+                ^ self.registers.r[self.configuration.read_reg1 as usize];
+            self.sp_addr0 ^= sp_mix as u32;
+            self.sp_addr0 &= SCRATCHPAD_L3_MASK64 as u32;
+            self.sp_addr1 ^= (sp_mix >> 32) as u32;
+            self.sp_addr1 &= SCRATCHPAD_L3_MASK64 as u32;
+
+            let sp_addr0 = self.sp_addr0 as usize;
+            let sp_addr1 = self.sp_addr1 as usize;
+            for i in 0..8 {
+                self.registers.r[i] ^= self.load64(sp_addr0 + 8 * i);
+            }
+            for i in 0..4 {
+                self.registers.f[i] = self.load_f128(sp_addr1 + 8 * i);
+            }
+            for i in 0..4 {
+                let e = self.load_f128(sp_addr1 + 8 * (4 + i));
+                self.registers.e[i] = self.mask_register_exponent_mantissa(e);
+            }
+
+            interpreter(self);
+
+            self.mx ^= (self.registers.r[self.configuration.read_reg2 as usize]
+                ^ self.registers.r[self.configuration.read_reg3 as usize])
+                as u32;
+            self.mx &= RANDOMX_CACHE_LINE_ASSIGN_MASK as u32;
+            let item_number = (self.dataset_offset + self.ma as u64) / RANDOMX_CACHE_LINE_SIZE;
+            let item = memory.item_in_range(item_number);
+            for (r, v) in self.registers.r.iter_mut().zip(item.iter()) {
+                *r ^= v;
+            }
+            std::mem::swap(&mut self.mx, &mut self.ma);
+
+            for i in 0..8 {
+                self.store64(sp_addr1 + 8 * i, self.registers.r[i]);
+            }
+            for i in 0..4 {
+                let [f_lo, f_hi] = self.registers.f[i];
+                let [e_lo, e_hi] = self.registers.e[i];
+                let lo = f_lo.to_bits() ^ e_lo.to_bits();
+                let hi = f_hi.to_bits() ^ e_hi.to_bits();
+                self.registers.f[i] = [f64::from_bits(lo), f64::from_bits(hi)];
+                self.store64(sp_addr0 + 16 * i, lo);
+                self.store64(sp_addr0 + 16 * i + 8, hi);
+            }
+            self.sp_addr0 = 0;
+            self.sp_addr1 = 0;
+            self.ic -= 1;
+        }
+    }
+
+    /// Compute the RandomX hash of `input`, as described in
+    /// [4.1](https://github.com/tevador/RandomX/blob/master/doc/specs.md#41-hash-calculation):
+    /// the scratchpad is initialized from the Blake2b-512 hash of `input`,
+    /// then `RANDOMX_PROGRAM_COUNT` programs are executed. Each program is
+    /// generated from the Blake2b-512 hash of the register file left by the
+    /// previous one. The environment can be reused for several hashes.
+    pub fn calculate_hash<'a>(
+        &mut self,
+        memory: impl Into<DatasetMemory<'a>>,
+        input: &[u8],
+    ) -> [u8; RANDOMX_HASH_SIZE] {
+        let mut seed: [u8; 64] = Blake2b512::digest(input).into();
+        self.init_scratchpad(&mut seed);
+        self.execute_programs(memory, seed);
+        self.finalize_hash()
+    }
+
+    /// Execute the `RANDOMX_PROGRAM_COUNT` programs of a hash over the
+    /// initialized scratchpad. The first program is generated from `seed`.
+    pub fn execute_programs<'a>(&mut self, memory: impl Into<DatasetMemory<'a>>, seed: [u8; 64]) {
+        let memory = memory.into();
+        self.fprc = ROUNDING_MODE_NEAREST;
+        let mut seed = seed;
+        for chain in 0..RANDOMX_PROGRAM_COUNT {
+            self.load_program(seed);
+            self.run(memory);
+            if chain < RANDOMX_PROGRAM_COUNT - 1 {
+                seed = Blake2b512::digest(self.register_file()).into();
+            }
         }
     }
+
+    /// Replace the a group registers by the AesHash1R fingerprint of the
+    /// scratchpad and return the Blake2b-256 hash of the register file.
+    pub fn finalize_hash(&mut self) -> [u8; RANDOMX_HASH_SIZE] {
+        let fingerprint = aes_hash1r_with(self.aes, &self.scratchpad)
+            .expect("the scratchpad is made of 64-byte blocks");
+        self.hash_register_file(fingerprint)
+    }
+
+    /// [VMEnvironment::finalize_hash], filling the scratchpad for the next
+    /// hash at the same time, like `hashAndFill` in the reference
+    /// implementation. Each block of the scratchpad is hashed, then replaced
+    /// by the output of AesGenerator1R seeded with `seed`. The result is the
+    /// same as [VMEnvironment::finalize_hash] followed by
+    /// [VMEnvironment::init_scratchpad], with a single pass over the
+    /// scratchpad.
+    pub fn finalize_hash_and_fill(&mut self, seed: &mut [u8; 64]) -> [u8; RANDOMX_HASH_SIZE] {
+        let mut hasher = AesHash1R::with_backend(self.aes);
+        for chunk in self.scratchpad.chunks_exact_mut(64) {
+            hasher.update(chunk);
+            *seed = aes_generator_1r_with(self.aes, *seed);
+            chunk.copy_from_slice(seed);
+        }
+        let fingerprint = hasher
+            .finalize()
+            .expect("the scratchpad is made of 64-byte blocks");
+        self.hash_register_file(fingerprint)
+    }
+
+    fn hash_register_file(&mut self, fingerprint: [u8; 64]) -> [u8; RANDOMX_HASH_SIZE] {
+        for (a, chunk) in self
+            .registers
+            .a
+            .iter_mut()
+            .zip(fingerprint.chunks_exact(16))
+        {
+            a[0] = u64::from_le_bytes(chunk[0..8].try_into().unwrap());
+            a[1] = u64::from_le_bytes(chunk[8..16].try_into().unwrap());
+        }
+        Blake2b::<U32>::digest(self.register_file()).into()
+    }
+
+    /// Serialize the register file, see [RegisterFile::to_bytes]
+    pub fn register_file(&self) -> [u8; 256] {
+        self.registers.to_bytes()
+    }
+
+    fn load64(&self, addr: usize) -> u64 {
+        u64::from_le_bytes(self.scratchpad[addr..addr + 8].try_into().unwrap())
+    }
+
+    fn store64(&mut self, addr: usize, value: u64) {
+        self.scratchpad[addr..addr + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Load two signed 32-bit integers from the scratchpad and convert them
+    /// to a pair of floating point values (low half first).
+    fn load_f128(&self, addr: usize) -> [f64; 2] {
+        let lo = i32::from_le_bytes(self.scratchpad[addr..addr + 4].try_into().unwrap());
+        let hi = i32::from_le_bytes(self.scratchpad[addr + 4..addr + 8].try_into().unwrap());
+        [lo as f64, hi as f64]
+    }
+
+    /// Set the exponent of the values loaded in the e group registers, using
+    /// `emask`.
+    fn mask_register_exponent_mantissa(&self, v: [f64; 2]) -> [f64; 2] {
+        let [lo, hi] = v;
+        let [emask_lo, emask_hi] = self.configuration.emask;
+        [
+            f64::from_bits((lo.to_bits() & DYNAMIC_MANTISSA_MASK) | emask_lo),
+            f64::from_bits((hi.to_bits() & DYNAMIC_MANTISSA_MASK) | emask_hi),
+        ]
+    }
 }
 
 #[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Instruction {
     // Integer instruction
     IADD_RS = 0,
@@ -222,6 +725,413 @@ pub enum Instruction {
     NOP = 29,
 }
 
-pub fn interpreter(_env: &mut VMEnvironment) {
-    // TODO
+/// Number of opcodes of each instruction, in the order in which the opcodes
+/// are assigned
+const INSTRUCTION_FREQUENCIES: [(Instruction, u64); 30] = [
+    (Instruction::IADD_RS, RANDOMX_FREQ_IADD_RS),
+    (Instruction::IADD_M, RANDOMX_FREQ_IADD_M),
+    (Instruction::ISUB_R, RANDOMX_FREQ_ISUB_R),
+    (Instruction::ISUB_M, RANDOMX_FREQ_ISUB_M),
+    (Instruction::IMUL_R, RANDOMX_FREQ_IMUL_R),
+    (Instruction::IMUL_M, RANDOMX_FREQ_IMUL_M),
+    (Instruction::IMULH_R, RANDOMX_FREQ_IMULH_R),
+    (Instruction::IMULH_M, RANDOMX_FREQ_IMULH_M),
+    (Instruction::ISMULH_R, RANDOMX_FREQ_ISMULH_R),
+    (Instruction::ISMULH_M, RANDOMX_FREQ_ISMULH_M),
+    (Instruction::IMUL_RCP, RANDOMX_FREQ_IMUL_RCP),
+    (Instruction::INEG_R, RANDOMX_FREQ_INEG_R),
+    (Instruction::IXOR_R, RANDOMX_FREQ_IXOR_R),
+    (Instruction::IXOR_M, RANDOMX_FREQ_IXOR_M),
+    (Instruction::IROR_R, RANDOMX_FREQ_IROR_R),
+    (Instruction::IROL_R, RANDOMX_FREQ_IROL_R),
+    (Instruction::ISWAP_R, RANDOMX_FREQ_ISWAP_R),
+    (Instruction::FSWAP_R, RANDOMX_FREQ_FSWAP_R),
+    (Instruction::FADD_R, RANDOMX_FREQ_FADD_R),
+    (Instruction::FADD_M, RANDOMX_FREQ_FADD_M),
+    (Instruction::FSUB_R, RANDOMX_FREQ_FSUB_R),
+    (Instruction::FSUB_M, RANDOMX_FREQ_FSUB_M),
+    (Instruction::FSCAL_R, RANDOMX_FREQ_FSCAL_R),
+    (Instruction::FMUL_R, RANDOMX_FREQ_FMUL_R),
+    (Instruction::FDIV_M, RANDOMX_FREQ_FDIV_M),
+    (Instruction::FSQRT_R, RANDOMX_FREQ_FSQRT_R),
+    (Instruction::CBRANCH, RANDOMX_FREQ_CBRANCH),
+    (Instruction::CFROUND, RANDOMX_FREQ_CFROUND),
+    (Instruction::ISTORE, RANDOMX_FREQ_ISTORE),
+    (Instruction::NOP, RANDOMX_FREQ_NOP),
+];
+
+const fn frequency_sum() -> u64 {
+    let mut sum = 0;
+    let mut i = 0;
+    while i < INSTRUCTION_FREQUENCIES.len() {
+        sum += INSTRUCTION_FREQUENCIES[i].1;
+        i += 1;
+    }
+    sum
+}
+
+/// Evaluated at compile time: an invalid set of frequencies is a build error
+const fn build_opcode_table() -> [Instruction; 256] {
+    assert!(
+        frequency_sum() == 256,
+        "The instruction frequencies must sum up to 256"
+    );
+    let mut table = [Instruction::NOP; 256];
+    let mut opcode = 0;
+    let mut i = 0;
+    while i < INSTRUCTION_FREQUENCIES.len() {
+        let (instruction, frequency) = INSTRUCTION_FREQUENCIES[i];
+        let mut j = 0;
+        while j < frequency {
+            table[opcode] = instruction;
+            opcode += 1;
+            j += 1;
+        }
+        i += 1;
+    }
+    table
+}
+
+/// The instruction corresponding to each of the 256 opcodes. Each
+/// instruction is assigned `RANDOMX_FREQ_*` consecutive opcodes, see
+/// [5](https://github.com/tevador/RandomX/blob/master/doc/specs.md#5-instruction-set).
+pub const OPCODE_TABLE: [Instruction; 256] = build_opcode_table();
+
+/// An instruction word with all its fields decoded, as described in
+/// [5.1](https://github.com/tevador/RandomX/blob/master/doc/specs.md#51-instruction-encoding).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    pub instruction: Instruction,
+    /// Index of the destination register, between 0 and 7
+    pub dst: usize,
+    /// Index of the source register, between 0 and 7
+    pub src: usize,
+    /// `mod.mem`: selects the scratchpad level of the memory operands
+    pub mod_mem: u8,
+    /// `mod.shift`: shift of `IADD_RS`
+    pub mod_shift: u8,
+    /// `mod.cond`: condition of `CBRANCH` and `ISTORE`
+    pub mod_cond: u8,
+    /// The immediate value sign-extended to 64 bits
+    pub imm: u64,
+    /// The raw immediate value, used as is by `IMUL_RCP` and `CFROUND`
+    pub imm32: u32,
+}
+
+/// Decode an instruction word using [OPCODE_TABLE]
+pub fn decode(i: EncodedInstruction) -> DecodedInstruction {
+    let modifier = mod_(i);
+    DecodedInstruction {
+        instruction: OPCODE_TABLE[opcode(i) as usize],
+        dst: (dst(i) % 8) as usize,
+        src: (src(i) % 8) as usize,
+        mod_mem: modifier % 4,
+        mod_shift: (modifier >> 2) % 4,
+        mod_cond: modifier >> 4,
+        imm: sign_extend_2s_compl(imm32(i)),
+        imm32: imm32(i),
+    }
+}
+
+/// An instruction compiled for the interpreter. The operands are resolved
+/// once per program, following the bytecode machine of the reference
+/// implementation. The JIT compiler starts from the same form.
+#[derive(Clone, Copy)]
+pub(crate) struct ByteCode {
+    pub(crate) instruction: Instruction,
+    pub(crate) dst: usize,
+    pub(crate) src: usize,
+    // The source operand is the immediate value instead of the register
+    // `src`. For memory operands, the address is computed from the immediate
+    // only.
+    pub(crate) src_imm: bool,
+    pub(crate) imm: u64,
+    pub(crate) shift: u32,
+    pub(crate) mem_mask: u64,
+    // Jump target of CBRANCH. The next executed instruction is target + 1.
+    pub(crate) target: isize,
+}
+
+/// Compile `program` into `bytecode`, reusing its memory
+fn compile_program(program: &[EncodedInstruction], bytecode: &mut Vec<ByteCode>) {
+    // Last instruction which modified each register. -1 means the beginning
+    // of the program.
+    let mut register_usage: [isize; 8] = [-1; 8];
+    bytecode.clear();
+    bytecode.extend(
+        program
+            .iter()
+            .enumerate()
+            .map(|(i, instr)| compile_instruction(*instr, i as isize, &mut register_usage)),
+    );
+}
+
+fn compile_instruction(
+    instr: EncodedInstruction,
+    i: isize,
+    register_usage: &mut [isize; 8],
+) -> ByteCode {
+    let instr = decode(instr);
+    let dst = instr.dst;
+    let src = instr.src;
+    let imm = instr.imm;
+    let mem_mask = if instr.mod_mem != 0 {
+        SCRATCHPAD_L1_MASK
+    } else {
+        SCRATCHPAD_L2_MASK
+    };
+
+    let mut bc = ByteCode {
+        instruction: instr.instruction,
+        dst,
+        src,
+        src_imm: false,
+        imm: 0,
+        shift: 0,
+        mem_mask: 0,
+        target: 0,
+    };
+    // Integer instruction reading the scratchpad. When the source and the
+    // destination are the same register, the address is the immediate value
+    // in L3.
+    let memory_operand = |bc: &mut ByteCode| {
+        bc.imm = imm;
+        if src != dst {
+            bc.mem_mask = mem_mask;
+        } else {
+            bc.src_imm = true;
+            bc.mem_mask = SCRATCHPAD_L3_MASK;
+        }
+    };
+    // Integer instruction using the immediate value when the source and the
+    // destination are the same register
+    let register_operand = |bc: &mut ByteCode| {
+        if src == dst {
+            bc.src_imm = true;
+            bc.imm = imm;
+        }
+    };
+
+    match instr.instruction {
+        Instruction::IADD_RS => {
+            bc.shift = instr.mod_shift as u32;
+            if dst == REGISTER_NEEDS_DISPLACEMENT {
+                bc.imm = imm;
+            }
+            register_usage[dst] = i;
+        }
+        Instruction::IADD_M
+        | Instruction::ISUB_M
+        | Instruction::IMUL_M
+        | Instruction::IMULH_M
+        | Instruction::ISMULH_M
+        | Instruction::IXOR_M => {
+            memory_operand(&mut bc);
+            register_usage[dst] = i;
+        }
+        Instruction::ISUB_R
+        | Instruction::IMUL_R
+        | Instruction::IXOR_R
+        | Instruction::IROR_R
+        | Instruction::IROL_R => {
+            register_operand(&mut bc);
+            register_usage[dst] = i;
+        }
+        Instruction::IMULH_R | Instruction::ISMULH_R | Instruction::INEG_R => {
+            register_usage[dst] = i;
+        }
+        Instruction::IMUL_RCP => {
+            let divisor = instr.imm32 as u64;
+            if !is_zero_or_power_of_2(divisor) {
+                // Executed as a multiplication by the reciprocal
+                bc.instruction = Instruction::IMUL_R;
+                bc.src_imm = true;
+                bc.imm = reciprocal(divisor);
+                register_usage[dst] = i;
+            } else {
+                bc.instruction = Instruction::NOP;
+            }
+        }
+        Instruction::ISWAP_R => {
+            if src != dst {
+                register_usage[dst] = i;
+                register_usage[src] = i;
+            } else {
+                bc.instruction = Instruction::NOP;
+            }
+        }
+        // dst selects one of the 4 f registers or one of the 4 e registers
+        Instruction::FSWAP_R => {}
+        Instruction::FADD_R | Instruction::FSUB_R | Instruction::FMUL_R => {
+            bc.dst = dst % 4;
+            bc.src = src % 4;
+        }
+        Instruction::FADD_M | Instruction::FSUB_M | Instruction::FDIV_M => {
+            bc.dst = dst % 4;
+            bc.imm = imm;
+            bc.mem_mask = mem_mask;
+        }
+        Instruction::FSCAL_R | Instruction::FSQRT_R => {
+            bc.dst = dst % 4;
+        }
+        Instruction::CBRANCH => {
+            bc.target = register_usage[dst];
+            let shift = instr.mod_cond as u64 + RANDOMX_JUMP_OFFSET;
+            bc.imm = imm | (1 << shift);
+            // Clear the bit below the condition mask. This limits the number of
+            // successive jumps to 2.
+            bc.imm &= !(1 << (shift - 1));
+            bc.mem_mask = CONDITION_MASK << shift;
+            // All registers are considered as modified
+            register_usage.fill(i);
+        }
+        Instruction::CFROUND => {
+            bc.imm = (instr.imm32 & 63) as u64;
+        }
+        Instruction::ISTORE => {
+            bc.imm = imm;
+            bc.mem_mask = if instr.mod_cond < STORE_L3_CONDITION {
+                mem_mask
+            } else {
+                SCRATCHPAD_L3_MASK
+            };
+        }
+        Instruction::NOP => {}
+    }
+    bc
+}
+
+/// Execute the loaded program once over the registers and the scratchpad of
+/// the environment. The semantics of each instruction are described in
+/// [5](https://github.com/tevador/RandomX/blob/master/doc/specs.md#5-instruction-set).
+pub fn interpreter(env: &mut VMEnvironment) {
+    let mut pc: isize = 0;
+    while (pc as usize) < env.bytecode.len() {
+        let bc = env.bytecode[pc as usize];
+        let dst = bc.dst;
+        let src = bc.src;
+        let mode = env.fprc;
+        let isrc = if bc.src_imm {
+            bc.imm
+        } else {
+            env.registers.r[src]
+        };
+        // Scratchpad address of the memory operand
+        let address = {
+            let base = if bc.src_imm { 0 } else { env.registers.r[src] };
+            (base.wrapping_add(bc.imm) & bc.mem_mask) as usize
+        };
+        match bc.instruction {
+            Instruction::IADD_RS => {
+                let r = &mut env.registers.r;
+                r[dst] = r[dst].wrapping_add(r[src] << bc.shift).wrapping_add(bc.imm)
+            }
+            Instruction::IADD_M => {
+                let v = env.load64(address);
+                env.registers.r[dst] = env.registers.r[dst].wrapping_add(v)
+            }
+            Instruction::ISUB_R => env.registers.r[dst] = env.registers.r[dst].wrapping_sub(isrc),
+            Instruction::ISUB_M => {
+                let v = env.load64(address);
+                env.registers.r[dst] = env.registers.r[dst].wrapping_sub(v)
+            }
+            Instruction::IMUL_R => env.registers.r[dst] = env.registers.r[dst].wrapping_mul(isrc),
+            Instruction::IMUL_M => {
+                let v = env.load64(address);
+                env.registers.r[dst] = env.registers.r[dst].wrapping_mul(v)
+            }
+            Instruction::IMULH_R => env.registers.r[dst] = mulh(env.registers.r[dst], isrc),
+            Instruction::IMULH_M => {
+                let v = env.load64(address);
+                env.registers.r[dst] = mulh(env.registers.r[dst], v)
+            }
+            Instruction::ISMULH_R => env.registers.r[dst] = smulh(env.registers.r[dst], isrc),
+            Instruction::ISMULH_M => {
+                let v = env.load64(address);
+                env.registers.r[dst] = smulh(env.registers.r[dst], v)
+            }
+            // Compiled as IMUL_R
+            Instruction::IMUL_RCP => unreachable!(),
+            Instruction::INEG_R => env.registers.r[dst] = env.registers.r[dst].wrapping_neg(),
+            Instruction::IXOR_R => env.registers.r[dst] ^= isrc,
+            Instruction::IXOR_M => env.registers.r[dst] ^= env.load64(address),
+            Instruction::IROR_R => {
+                env.registers.r[dst] = env.registers.r[dst].rotate_right((isrc & 63) as u32)
+            }
+            Instruction::IROL_R => {
+                env.registers.r[dst] = env.registers.r[dst].rotate_left((isrc & 63) as u32)
+            }
+            Instruction::ISWAP_R => env.registers.r.swap(dst, src),
+            Instruction::FSWAP_R => {
+                let reg = if dst < 4 {
+                    &mut env.registers.f[dst]
+                } else {
+                    &mut env.registers.e[dst - 4]
+                };
+                reg.swap(0, 1);
+            }
+            Instruction::FADD_R => {
+                let a = env.registers.a[src].map(f64::from_bits);
+                let f = &mut env.registers.f[dst];
+                f[0] = fadd(f[0], a[0], mode);
+                f[1] = fadd(f[1], a[1], mode);
+            }
+            Instruction::FADD_M => {
+                let v = env.load_f128(address);
+                let f = &mut env.registers.f[dst];
+                f[0] = fadd(f[0], v[0], mode);
+                f[1] = fadd(f[1], v[1], mode);
+            }
+            Instruction::FSUB_R => {
+                let a = env.registers.a[src].map(f64::from_bits);
+                let f = &mut env.registers.f[dst];
+                f[0] = fsub(f[0], a[0], mode);
+                f[1] = fsub(f[1], a[1], mode);
+            }
+            Instruction::FSUB_M => {
+                let v = env.load_f128(address);
+                let f = &mut env.registers.f[dst];
+                f[0] = fsub(f[0], v[0], mode);
+                f[1] = fsub(f[1], v[1], mode);
+            }
+            Instruction::FSCAL_R => {
+                let f = &mut env.registers.f[dst];
+                f[0] = f64::from_bits(f[0].to_bits() ^ FSCAL_MASK);
+                f[1] = f64::from_bits(f[1].to_bits() ^ FSCAL_MASK);
+            }
+            Instruction::FMUL_R => {
+                let a = env.registers.a[src].map(f64::from_bits);
+                let e = &mut env.registers.e[dst];
+                e[0] = fmul(e[0], a[0], mode);
+                e[1] = fmul(e[1], a[1], mode);
+            }
+            Instruction::FDIV_M => {
+                let v = env.mask_register_exponent_mantissa(env.load_f128(address));
+                let e = &mut env.registers.e[dst];
+                e[0] = fdiv(e[0], v[0], mode);
+                e[1] = fdiv(e[1], v[1], mode);
+            }
+            Instruction::FSQRT_R => {
+                let e = &mut env.registers.e[dst];
+                e[0] = fsqrt(e[0], mode);
+                e[1] = fsqrt(e[1], mode);
+            }
+            Instruction::CBRANCH => {
+                let r = &mut env.registers.r;
+                r[dst] = r[dst].wrapping_add(bc.imm);
+                if r[dst] & bc.mem_mask == 0 {
+                    pc = bc.target;
+                }
+            }
+            Instruction::CFROUND => {
+                env.fprc = (env.registers.r[src].rotate_right(bc.imm as u32) % 4) as u32;
+            }
+            Instruction::ISTORE => {
+                let addr = (env.registers.r[dst].wrapping_add(bc.imm) & bc.mem_mask) as usize;
+                env.store64(addr, env.registers.r[src]);
+            }
+            Instruction::NOP => {}
+        }
+        pc += 1;
+    }
 }
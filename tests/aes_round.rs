@@ -0,0 +1,81 @@
+use aes::hazmat::{cipher_round, equiv_inv_cipher_round};
+use aes::Block;
+use randomx::aes_round::AesBackend;
+use randomx::flags::{get_flags, RandomXFlags};
+use randomx::Error;
+
+/// Deterministic pseudo-random 16-byte blocks (xorshift64*)
+fn random_blocks(count: usize) -> Vec<[u8; 16]> {
+    let mut x = 0x0123456789ABCDEFu64;
+    let mut next = || {
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        x.wrapping_mul(0x2545F4914F6CDD1D)
+    };
+    (0..count)
+        .map(|_| {
+            let mut block = [0u8; 16];
+            block[0..8].copy_from_slice(&next().to_le_bytes());
+            block[8..16].copy_from_slice(&next().to_le_bytes());
+            block
+        })
+        .collect()
+}
+
+fn backends() -> Vec<AesBackend> {
+    let mut backends = vec![AesBackend::Software];
+    if let Ok(hardware) = AesBackend::from_flags(RandomXFlags::HARD_AES) {
+        backends.push(hardware);
+    }
+    backends
+}
+
+#[test]
+fn test_aes_enc_round() {
+    let blocks = random_blocks(2000);
+    for backend in backends() {
+        for pair in blocks.chunks_exact(2) {
+            let (mut state, key) = (pair[0], pair[1]);
+            let mut exp = Block::from(state);
+            cipher_round(&mut exp, &Block::from(key));
+            backend.enc_round(&mut state, &key);
+            assert_eq!(state, exp.as_slice(), "{:?}", backend);
+        }
+    }
+}
+
+#[test]
+fn test_aes_dec_round() {
+    let blocks = random_blocks(2000);
+    for backend in backends() {
+        for pair in blocks.chunks_exact(2) {
+            let (mut state, key) = (pair[0], pair[1]);
+            let mut exp = Block::from(state);
+            equiv_inv_cipher_round(&mut exp, &Block::from(key));
+            backend.dec_round(&mut state, &key);
+            assert_eq!(state, exp.as_slice(), "{:?}", backend);
+        }
+    }
+}
+
+#[test]
+fn test_aes_backend_from_flags() {
+    assert_eq!(
+        AesBackend::from_flags(RandomXFlags::DEFAULT),
+        Ok(AesBackend::Software)
+    );
+    if AesBackend::hardware_available() {
+        assert!(get_flags().contains(RandomXFlags::HARD_AES));
+        assert!(matches!(
+            AesBackend::from_flags(RandomXFlags::HARD_AES),
+            Ok(AesBackend::Hardware(_))
+        ));
+    } else {
+        assert!(!get_flags().contains(RandomXFlags::HARD_AES));
+        assert_eq!(
+            AesBackend::from_flags(RandomXFlags::HARD_AES),
+            Err(Error::UnsupportedFlag(RandomXFlags::HARD_AES))
+        );
+    }
+}
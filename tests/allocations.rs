@@ -0,0 +1,72 @@
+//! The global allocator of this test binary counts the allocations of each
+//! thread, to check that computing hashes does not allocate.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use randomx::flags::RandomXFlags;
+use randomx::vm::Vm;
+
+mod common;
+use common::*;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+// SAFETY: the allocations are forwarded to the system allocator
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+/// Check that the VM does not allocate once it has computed a first hash
+fn check_no_allocations(flags: RandomXFlags) {
+    let mut vm = Vm::new(flags, Some(cache_000()), None).unwrap();
+    let first_hash = vm.calculate_hash(THIS_IS_A_TEST);
+
+    let before = allocations();
+    let hashes = [vm.hash(THIS_IS_A_TEST), vm.hash(LOREM_IPSUM)];
+    assert_eq!(allocations() - before, 0, "flags {:?}", flags);
+
+    let before = allocations();
+    vm.calculate_hash_first(THIS_IS_A_TEST);
+    let pipelined_hash = vm.calculate_hash_next(LOREM_IPSUM).unwrap();
+    assert_eq!(vm.calculate_hash_last(), Ok(hashes[1]));
+    assert_eq!(allocations() - before, 0, "flags {:?}", flags);
+    assert_eq!(pipelined_hash, hashes[0]);
+
+    assert_eq!(first_hash, HASH_000_THIS_IS_A_TEST);
+    assert_eq!(hashes, [HASH_000_THIS_IS_A_TEST, HASH_000_LOREM_IPSUM]);
+}
+
+#[test]
+fn test_vm_hash_does_not_allocate() {
+    check_no_allocations(RandomXFlags::DEFAULT);
+    #[cfg(feature = "jit")]
+    check_no_allocations(RandomXFlags::JIT | RandomXFlags::SECURE);
+}
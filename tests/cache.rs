@@ -0,0 +1,39 @@
+use randomx::cache::Cache;
+use randomx::parameters::{RANDOMX_ARGON_MEMORY, RANDOMX_CACHE_SIZE};
+
+mod common;
+use common::*;
+
+#[test]
+fn test_cache_new_test_key_000() {
+    // "randomx_init_cache"
+    let cache = cache_000();
+
+    assert_eq!(cache.memory.len(), RANDOMX_ARGON_MEMORY as usize);
+    assert_eq!(cache.word(0), 0x191e0e1d23c02186);
+    assert_eq!(cache.word(1568413), 0xf1b62fe6210bf8b1);
+    assert_eq!(
+        cache.word(RANDOMX_CACHE_SIZE as usize / 8 - 1),
+        0x1f47f056d05cd99b
+    );
+}
+
+#[test]
+fn test_cache_dataset_item_test_key_000() {
+    let cache = cache_000();
+    for (item_number, exp_r0) in DATASET_ITEMS_R0_000 {
+        let item = cache.dataset_item(item_number);
+        assert_eq!(u64::from_le_bytes(item[0..8].try_into().unwrap()), exp_r0);
+        assert_eq!(
+            item[0..8],
+            cache.init_dataset_item(item_number)[0].to_le_bytes()
+        );
+    }
+}
+
+#[test]
+fn test_cache_new_long_key() {
+    // Keys longer than the BlakeGenerator seed are valid
+    let key = [0x42; 100];
+    assert!(Cache::new(&key).is_ok());
+}
@@ -0,0 +1,122 @@
+//! Fixtures shared by the integration tests. Unless stated otherwise, the
+//! vectors come from the reference implementation, commit 89aba80,
+//! src/tests/tests.cpp.
+#![allow(dead_code)]
+
+use std::sync::OnceLock;
+
+use blake2::{Blake2b512, Digest};
+use randomx::cache::Cache;
+use randomx::parameters::RANDOMX_PROGRAM_COUNT;
+use randomx::vm::VMEnvironment;
+
+pub const KEY_000: &[u8] = b"test key 000";
+pub const KEY_001: &[u8] = b"test key 001";
+
+pub const THIS_IS_A_TEST: &[u8] = b"This is a test";
+pub const LOREM_IPSUM: &[u8] = b"Lorem ipsum dolor sit amet";
+pub const SED_DO_EIUSMOD: &[u8] =
+    b"sed do eiusmod tempor incididunt ut labore et dolore magna aliqua";
+/// A Monero block hashing blob
+pub const BLOCK_BLOB: &[u8] = &[
+    0x0b, 0x0b, 0x98, 0xbe, 0xa7, 0xe8, 0x05, 0xe0, 0x01, 0x0a, 0x21, 0x26, 0xd2, 0x87, 0xa2, 0xa0,
+    0xcc, 0x83, 0x3d, 0x31, 0x2c, 0xb7, 0x86, 0x38, 0x5a, 0x7c, 0x2f, 0x9d, 0xe6, 0x9d, 0x25, 0x53,
+    0x7f, 0x58, 0x4a, 0x9b, 0xc9, 0x97, 0x7b, 0x00, 0x00, 0x00, 0x00, 0x66, 0x6f, 0xd8, 0x75, 0x3b,
+    0xf6, 0x1a, 0x86, 0x31, 0xf1, 0x29, 0x84, 0xe3, 0xfd, 0x44, 0xf4, 0x01, 0x4e, 0xca, 0x62, 0x92,
+    0x76, 0x81, 0x7b, 0x56, 0xf3, 0x2e, 0x9b, 0x68, 0xbd, 0x82, 0xf4, 0x16,
+];
+
+/// Hash of [THIS_IS_A_TEST] with [KEY_000]
+pub const HASH_000_THIS_IS_A_TEST: [u8; 32] = [
+    0x63, 0x91, 0x83, 0xaa, 0xe1, 0xbf, 0x4c, 0x9a, 0x35, 0x88, 0x4c, 0xb4, 0x6b, 0x09, 0xca, 0xd9,
+    0x17, 0x5f, 0x04, 0xef, 0xd7, 0x68, 0x4e, 0x72, 0x62, 0xa0, 0xac, 0x1c, 0x2f, 0x0b, 0x4e, 0x3f,
+];
+/// Hash of [LOREM_IPSUM] with [KEY_000]
+pub const HASH_000_LOREM_IPSUM: [u8; 32] = [
+    0x30, 0x0a, 0x0a, 0xdb, 0x47, 0x60, 0x3d, 0xed, 0xb4, 0x22, 0x28, 0xcc, 0xb2, 0xb2, 0x11, 0x10,
+    0x4f, 0x4d, 0xa4, 0x5a, 0xf7, 0x09, 0xcd, 0x75, 0x47, 0xcd, 0x04, 0x9e, 0x94, 0x89, 0xc9, 0x69,
+];
+/// Hash of [SED_DO_EIUSMOD] with [KEY_000]
+pub const HASH_000_SED_DO_EIUSMOD: [u8; 32] = [
+    0xc3, 0x6d, 0x4e, 0xd4, 0x19, 0x1e, 0x61, 0x73, 0x09, 0x86, 0x7e, 0xd6, 0x6a, 0x44, 0x3b, 0xe4,
+    0x07, 0x50, 0x14, 0xe2, 0xb0, 0x61, 0xbc, 0xda, 0xf9, 0xce, 0x7b, 0x72, 0x1d, 0x2b, 0x77, 0xa8,
+];
+/// Hash of [SED_DO_EIUSMOD] with [KEY_001]
+pub const HASH_001_SED_DO_EIUSMOD: [u8; 32] = [
+    0xe9, 0xff, 0x45, 0x03, 0x20, 0x1c, 0x0c, 0x2c, 0xca, 0x26, 0xd2, 0x85, 0xc9, 0x3a, 0xe8, 0x83,
+    0xf9, 0xb1, 0xd3, 0x0c, 0x9e, 0xb2, 0x40, 0xb8, 0x20, 0x75, 0x6f, 0x2d, 0x5a, 0x79, 0x05, 0xfc,
+];
+/// Hash of [BLOCK_BLOB] with [KEY_001]
+pub const HASH_001_BLOCK_BLOB: [u8; 32] = [
+    0xc5, 0x64, 0x14, 0x12, 0x1a, 0xcd, 0xa1, 0x71, 0x3c, 0x2f, 0x2a, 0x81, 0x9d, 0x8a, 0xe3, 0x8a,
+    0xed, 0x7c, 0x80, 0xc3, 0x5c, 0x2a, 0x76, 0x92, 0x98, 0xd3, 0x4f, 0x03, 0x83, 0x3c, 0xd5, 0xf1,
+];
+
+/// All the hash vectors, as (key, input, hash)
+pub const HASH_VECTORS: [(&[u8], &[u8], [u8; 32]); 5] = [
+    (KEY_000, THIS_IS_A_TEST, HASH_000_THIS_IS_A_TEST),
+    (KEY_000, LOREM_IPSUM, HASH_000_LOREM_IPSUM),
+    (KEY_000, SED_DO_EIUSMOD, HASH_000_SED_DO_EIUSMOD),
+    (KEY_001, SED_DO_EIUSMOD, HASH_001_SED_DO_EIUSMOD),
+    (KEY_001, BLOCK_BLOB, HASH_001_BLOCK_BLOB),
+];
+
+/// The first register of some Dataset items with [KEY_000], as (item
+/// number, r0) ("randomx_init_dataset")
+pub const DATASET_ITEMS_R0_000: [(u64, u64); 4] = [
+    (0, 0x680588a85ae222db),
+    (10000000, 0x7943a1f6186ffb72),
+    (20000000, 0x9035244d718095e1),
+    (30000000, 0x145a5091f7853099),
+];
+
+/// The 64-byte input of the AES test vectors: the first 32 bytes are set,
+/// the others are zero
+pub fn aes_test_input() -> [u8; 64] {
+    let mut input = [0u8; 64];
+    input[0..32].copy_from_slice(&[
+        0x6c, 0x19, 0x53, 0x6e, 0xb2, 0xde, 0x31, 0xb6, 0xc0, 0x06, 0x5f, 0x7f, 0x11, 0x6e, 0x86,
+        0xf9, 0x60, 0xd8, 0xaf, 0x0c, 0x57, 0x21, 0x0a, 0x65, 0x84, 0xc3, 0x23, 0x7b, 0x9d, 0x06,
+        0x4d, 0xc7,
+    ]);
+    input
+}
+
+/// The first 32 bytes of the output of `fillAes1Rx4` for
+/// [aes_test_input] (tests.cpp#L172)
+pub const AES_GENERATOR_1R_OUTPUT_PREFIX: [u8; 32] = [
+    0xfa, 0x89, 0x39, 0x7d, 0xd6, 0xca, 0x42, 0x25, 0x13, 0xae, 0xad, 0xba, 0x3f, 0x12, 0x4b, 0x55,
+    0x40, 0x32, 0x4c, 0x4a, 0xd4, 0xb6, 0xdb, 0x43, 0x43, 0x94, 0x30, 0x7a, 0x17, 0xc8, 0x33, 0xab,
+];
+
+/// The Cache of [KEY_000], built once per test binary
+pub fn cache_000() -> &'static Cache {
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+    CACHE.get_or_init(|| Cache::new(KEY_000).unwrap())
+}
+
+/// The Cache of [KEY_001], built once per test binary
+pub fn cache_001() -> &'static Cache {
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+    CACHE.get_or_init(|| Cache::new(KEY_001).unwrap())
+}
+
+/// Initialize the scratchpad from `input` and execute the programs of the
+/// hash one by one, like [VMEnvironment::calculate_hash].
+/// `run_program` is called with the seed of each program once it is loaded
+/// and must run it.
+/// The returned environment is ready for [VMEnvironment::finalize_hash].
+pub fn execute_programs_with(
+    input: &[u8],
+    mut run_program: impl FnMut(&mut VMEnvironment, [u8; 64]),
+) -> VMEnvironment {
+    let mut vm_env = VMEnvironment::new().unwrap();
+    let mut seed: [u8; 64] = Blake2b512::digest(input).into();
+    vm_env.init_scratchpad(&mut seed);
+    for _ in 0..RANDOMX_PROGRAM_COUNT {
+        vm_env.load_program(seed);
+        run_program(&mut vm_env, seed);
+        seed = Blake2b512::digest(vm_env.register_file()).into();
+    }
+    vm_env
+}
@@ -0,0 +1,140 @@
+use std::thread;
+
+use randomx::dataset::{init_items, Dataset};
+use randomx::parameters::RANDOMX_DATASET_ITEM_COUNT;
+use randomx::vm::DatasetMemory;
+use randomx::Error;
+
+mod common;
+use common::*;
+
+#[test]
+fn test_dataset_init_ranges() {
+    let cache = cache_000();
+    let mut dataset = Dataset::new().unwrap();
+
+    dataset.init(cache, 0, 16).unwrap();
+    dataset
+        .init(cache, RANDOMX_DATASET_ITEM_COUNT - 16, 16)
+        .unwrap();
+    for item_number in (0..16).chain(RANDOMX_DATASET_ITEM_COUNT - 16..RANDOMX_DATASET_ITEM_COUNT) {
+        assert_eq!(
+            dataset.item(item_number).unwrap(),
+            cache.dataset_item(item_number)
+        );
+    }
+    // Items outside of the initialized ranges are untouched
+    assert_eq!(dataset.item(16).unwrap(), [0; 64]);
+
+    let (item_number, exp_r0) = DATASET_ITEMS_R0_000[0];
+    assert_eq!(
+        u64::from_le_bytes(dataset.item(item_number).unwrap()[0..8].try_into().unwrap()),
+        exp_r0
+    );
+}
+
+#[test]
+fn test_dataset_init_items_split() {
+    let cache = cache_000();
+
+    // Initializing a range in several parts gives the same items
+    let mut items = vec![0u8; 64 * 64];
+    init_items(cache, 10000000, &mut items);
+    let mut parts = vec![0u8; 64 * 64];
+    for (i, part) in parts.chunks_mut(64 * 16).enumerate() {
+        init_items(cache, 10000000 + 16 * i as u64, part);
+    }
+    assert_eq!(items, parts);
+    assert_eq!(items[0..64], cache.dataset_item(10000000));
+}
+
+#[test]
+fn test_dataset_chunks_mut() {
+    let cache = cache_000();
+    let mut dataset = Dataset::new().unwrap();
+
+    let items_per_chunk = (RANDOMX_DATASET_ITEM_COUNT as usize).div_ceil(3);
+    let chunks: Vec<_> = dataset.chunks_mut(items_per_chunk).collect();
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(
+        chunks.iter().map(|chunk| chunk.item_count()).sum::<u64>(),
+        RANDOMX_DATASET_ITEM_COUNT
+    );
+    // The beginning of each chunk is initialized from its own thread
+    thread::scope(|s| {
+        for chunk in chunks {
+            assert_eq!(chunk.start_item % items_per_chunk as u64, 0);
+            s.spawn(move || init_items(cache, chunk.start_item, &mut chunk.memory[..64 * 16]));
+        }
+    });
+    for chunk in 0..3 {
+        let start_item = (chunk * items_per_chunk) as u64;
+        for item_number in start_item..start_item + 16 {
+            assert_eq!(
+                dataset.item(item_number).unwrap(),
+                cache.dataset_item(item_number)
+            );
+        }
+    }
+
+    // The items were not computed by the chunks themselves
+    assert!(!dataset.is_initialized());
+
+    // The item count is not a multiple of 16, the last chunk is shorter
+    let mut last_chunk = dataset.chunks_mut(16).last().unwrap();
+    let start_item = last_chunk.start_item;
+    assert_eq!(last_chunk.item_count(), RANDOMX_DATASET_ITEM_COUNT % 16);
+    assert_eq!(
+        start_item + last_chunk.item_count(),
+        RANDOMX_DATASET_ITEM_COUNT
+    );
+    last_chunk.init(cache);
+    assert!(dataset.is_initialized());
+    for item_number in start_item..RANDOMX_DATASET_ITEM_COUNT {
+        assert_eq!(
+            dataset.item(item_number).unwrap(),
+            cache.dataset_item(item_number)
+        );
+    }
+}
+
+#[test]
+fn test_dataset_memory_fast_same_as_light() {
+    let cache = cache_000();
+    let mut dataset = Dataset::new().unwrap();
+    dataset.init(cache, 20000000, 4).unwrap();
+
+    let light = DatasetMemory::from(cache);
+    let fast = DatasetMemory::from(&dataset);
+    for item_number in 20000000..20000004 {
+        assert_eq!(light.item(item_number), fast.item(item_number));
+    }
+}
+
+#[test]
+fn test_dataset_invalid_item_range() {
+    let cache = cache_000();
+    let mut dataset = Dataset::new().unwrap();
+    assert_eq!(
+        dataset.init(cache, RANDOMX_DATASET_ITEM_COUNT - 1, 2),
+        Err(Error::InvalidItemRange {
+            start_item: RANDOMX_DATASET_ITEM_COUNT - 1,
+            count: 2
+        })
+    );
+    assert!(dataset.init(cache, u64::MAX, 2).is_err());
+
+    let out_of_range = Err(Error::InvalidItemRange {
+        start_item: RANDOMX_DATASET_ITEM_COUNT,
+        count: 1,
+    });
+    assert_eq!(dataset.item(RANDOMX_DATASET_ITEM_COUNT), out_of_range);
+    assert!(dataset.item(RANDOMX_DATASET_ITEM_COUNT - 1).is_ok());
+    assert!(dataset.item(u64::MAX).is_err());
+    for memory in [DatasetMemory::from(cache), DatasetMemory::from(&dataset)] {
+        assert_eq!(
+            memory.item(RANDOMX_DATASET_ITEM_COUNT).err(),
+            out_of_range.err()
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use randomx::cache::Cache;
+use randomx::dataset::Dataset;
+use randomx::flags::{get_flags, RandomXFlags};
+use randomx::memory::PageSize;
+use randomx::parameters::{
+    RANDOMX_CACHE_LINE_SIZE, RANDOMX_PROGRAM_COUNT, RANDOMX_PROGRAM_ITERATIONS,
+};
+use randomx::vm::{VMEnvironment, Vm};
+use randomx::{calculate_commitment, calculate_hash, Error};
+
+mod common;
+use common::*;
+
+#[test]
+fn test_calculate_hash_test_vectors() {
+    for (key, input, exp_hash) in HASH_VECTORS {
+        assert_eq!(calculate_hash(key, input), Ok(exp_hash));
+    }
+}
+
+#[test]
+fn test_calculate_commitment() {
+    // Test vector from the reference implementation, version 1.2.0,
+    // src/tests/tests.cpp ("Commitment test")
+    let input = THIS_IS_A_TEST;
+    let hash = calculate_hash(KEY_000, input).unwrap();
+    let exp_commitment: [u8; 32] = [
+        0xd5, 0x3c, 0xcf, 0x34, 0x8b, 0x75, 0x29, 0x1b, 0x7b, 0xe7, 0x6f, 0x0a, 0x7a, 0xc8, 0x20,
+        0x8b, 0xbc, 0xed, 0x73, 0x4b, 0x91, 0x2f, 0x6f, 0xca, 0x60, 0x53, 0x9a, 0xb6, 0xf8, 0x6b,
+        0xe9, 0x19,
+    ];
+    assert_eq!(calculate_commitment(input, &hash), exp_commitment);
+
+    // Vectors computed with Python's hashlib.blake2b(input + hash,
+    // digest_size=32)
+    let hash = HASH_000_LOREM_IPSUM;
+    let exp_commitment: [u8; 32] = [
+        0x26, 0xbb, 0x90, 0x91, 0xb9, 0xe9, 0x46, 0xa8, 0xd3, 0xb1, 0x8f, 0x41, 0x95, 0x37, 0xc4,
+        0x5d, 0x04, 0x1c, 0xfd, 0x2c, 0x06, 0x3f, 0x16, 0x8c, 0x93, 0x41, 0x41, 0x34, 0xd5, 0xd5,
+        0x31, 0x89,
+    ];
+    assert_eq!(calculate_commitment(LOREM_IPSUM, &hash), exp_commitment);
+    let exp_commitment: [u8; 32] = [
+        0x89, 0xeb, 0x0d, 0x6a, 0x8a, 0x69, 0x1d, 0xae, 0x2c, 0xd1, 0x5e, 0xd0, 0x36, 0x99, 0x31,
+        0xce, 0x0a, 0x94, 0x9e, 0xca, 0xfa, 0x5c, 0x3f, 0x93, 0xf8, 0x12, 0x18, 0x33, 0x64, 0x6e,
+        0x15, 0xc3,
+    ];
+    assert_eq!(calculate_commitment(b"", &[0; 32]), exp_commitment);
+}
+
+#[test]
+fn test_vm_environment_calculate_hash_reuse() {
+    // The same cache and environment are used for several hashes
+    let cache = cache_000();
+    let mut vm_env = VMEnvironment::new().unwrap();
+    assert_eq!(
+        vm_env.calculate_hash(cache, THIS_IS_A_TEST),
+        HASH_000_THIS_IS_A_TEST
+    );
+    assert_eq!(
+        vm_env.calculate_hash(cache, LOREM_IPSUM),
+        HASH_000_LOREM_IPSUM
+    );
+}
+
+#[test]
+fn test_vm_light_mode() {
+    let cache = cache_001();
+    let mut vm = Vm::new(RandomXFlags::DEFAULT, Some(cache), None).unwrap();
+    assert_eq!(vm.calculate_hash(SED_DO_EIUSMOD), HASH_001_SED_DO_EIUSMOD);
+}
+
+#[test]
+fn test_vm_light_mode_hard_aes() {
+    if !get_flags().contains(RandomXFlags::HARD_AES) {
+        return;
+    }
+    let cache = cache_000();
+    let mut vm = Vm::new(RandomXFlags::HARD_AES, Some(cache), None).unwrap();
+    assert_eq!(vm.calculate_hash(THIS_IS_A_TEST), HASH_000_THIS_IS_A_TEST);
+}
+
+#[test]
+fn test_vm_hash_batch() {
+    let cache = cache_000();
+    let mut vm = Vm::new(RandomXFlags::DEFAULT, Some(cache), None).unwrap();
+    let inputs = [THIS_IS_A_TEST, LOREM_IPSUM, SED_DO_EIUSMOD];
+    let exp_hashes = [
+        HASH_000_THIS_IS_A_TEST,
+        HASH_000_LOREM_IPSUM,
+        HASH_000_SED_DO_EIUSMOD,
+    ];
+    assert_eq!(vm.hash_batch(&inputs), exp_hashes);
+    assert_eq!(vm.hash_batch(&inputs[1..2]), exp_hashes[1..2]);
+    assert!(vm.hash_batch::<&[u8]>(&[]).is_empty());
+
+    // The pipeline gives the same results as independent hashes, including
+    // for inputs of different lengths and repeated inputs
+    let inputs = [&b""[..], &[0xAB; 200], b"", b"x"];
+    let exp_hashes: Vec<[u8; 32]> = inputs
+        .iter()
+        .map(|input| vm.calculate_hash(input))
+        .collect();
+    vm.calculate_hash_first(inputs[0]);
+    let mut hashes = Vec::new();
+    for input in &inputs[1..] {
+        hashes.push(vm.calculate_hash_next(input).unwrap());
+    }
+    hashes.push(vm.calculate_hash_last().unwrap());
+    assert_eq!(hashes, exp_hashes);
+}
+
+#[test]
+fn test_vm_calculate_hash_last_without_first() {
+    let cache = cache_000();
+    let mut vm = Vm::new(RandomXFlags::DEFAULT, Some(cache), None).unwrap();
+    assert_eq!(vm.calculate_hash_last(), Err(Error::NoHashInProgress));
+    vm.calculate_hash_first(THIS_IS_A_TEST);
+    // The pipeline is abandoned
+    vm.calculate_hash(THIS_IS_A_TEST);
+    assert_eq!(vm.calculate_hash_last(), Err(Error::NoHashInProgress));
+    assert_eq!(
+        vm.calculate_hash_next(THIS_IS_A_TEST),
+        Err(Error::NoHashInProgress)
+    );
+    vm.calculate_hash_first(THIS_IS_A_TEST);
+    vm.hash_batch(&[LOREM_IPSUM]);
+    assert_eq!(vm.calculate_hash_last(), Err(Error::NoHashInProgress));
+}
+
+#[test]
+fn test_vm_light_mode_large_pages() {
+    let cache = Cache::with_flags(KEY_000, RandomXFlags::LARGE_PAGES).unwrap();
+    let mut vm = Vm::new(RandomXFlags::LARGE_PAGES, Some(&cache), None).unwrap();
+    // The memory falls back to smaller pages if huge pages are not available
+    assert!(cache.memory.page_size() <= PageSize::Huge2M);
+    assert!(vm.scratchpad_page_size() <= PageSize::Huge2M);
+    assert_eq!(vm.calculate_hash(THIS_IS_A_TEST), HASH_000_THIS_IS_A_TEST);
+}
+
+#[test]
+fn test_vm_fast_mode_test_vector() {
+    // Only the Dataset items read by the hash are initialized. They are
+    // recorded by running the programs one iteration at a time in light
+    // mode.
+    let cache = cache_000();
+    let mut item_numbers = Vec::new();
+    let mut vm_env = execute_programs_with(THIS_IS_A_TEST, |vm_env, _| {
+        while vm_env.ic > 0 {
+            item_numbers.push((vm_env.dataset_offset + vm_env.ma as u64) / RANDOMX_CACHE_LINE_SIZE);
+            let remaining = vm_env.ic - 1;
+            vm_env.ic = 1;
+            vm_env.run(cache);
+            vm_env.ic = remaining;
+        }
+    });
+    assert_eq!(vm_env.finalize_hash(), HASH_000_THIS_IS_A_TEST);
+    assert_eq!(
+        item_numbers.len(),
+        RANDOMX_PROGRAM_COUNT as usize * RANDOMX_PROGRAM_ITERATIONS as usize
+    );
+
+    let mut dataset = Dataset::new().unwrap();
+    for item_number in item_numbers {
+        dataset.init(cache, item_number, 1).unwrap();
+    }
+    let mut vm = Vm::new(RandomXFlags::FULL_MEM, None, Some(&dataset)).unwrap();
+    assert_eq!(vm.calculate_hash(THIS_IS_A_TEST), HASH_000_THIS_IS_A_TEST);
+}
+
+#[test]
+#[ignore = "initializes the whole Dataset, which takes several minutes per core"]
+fn test_vm_fast_mode_same_as_light_mode() {
+    let cache = cache_000();
+    let mut dataset = Dataset::new().unwrap();
+    dataset.init_all(cache);
+
+    let mut light_vm = Vm::new(RandomXFlags::DEFAULT, Some(cache), None).unwrap();
+    let mut fast_vm = Vm::new(RandomXFlags::FULL_MEM, None, Some(&dataset)).unwrap();
+    for input in [THIS_IS_A_TEST, LOREM_IPSUM] {
+        assert_eq!(
+            light_vm.calculate_hash(input),
+            fast_vm.calculate_hash(input)
+        );
+    }
+}
@@ -14,3 +14,20 @@ fn test_unitest_from_ref_implementation() {
     let hexa_output = res.to_be_bytes();
     assert_eq!(exp_hexa_output, hexa_output)
 }
+
+#[test]
+fn test_directed_rounding_overflow() {
+    use randomx::helpers::{fadd, fdiv, fmul, ROUNDING_MODE_UP, ROUNDING_MODE_ZERO};
+
+    // An overflow saturates to the largest finite value when rounding toward
+    // zero
+    assert_eq!(fmul(f64::MAX, 2.0, ROUNDING_MODE_ZERO), f64::MAX);
+    assert_eq!(fadd(f64::MAX, f64::MAX, ROUNDING_MODE_ZERO), f64::MAX);
+    assert_eq!(fdiv(f64::MAX, 0.5, ROUNDING_MODE_ZERO), f64::MAX);
+    assert_eq!(fmul(-f64::MAX, 2.0, ROUNDING_MODE_UP), -f64::MAX);
+
+    // The infinity computed from an infinite operand is exact
+    assert_eq!(fmul(f64::INFINITY, 2.0, ROUNDING_MODE_ZERO), f64::INFINITY);
+    assert_eq!(fadd(f64::INFINITY, 1.0, ROUNDING_MODE_ZERO), f64::INFINITY);
+    assert_eq!(fdiv(f64::INFINITY, 2.0, ROUNDING_MODE_ZERO), f64::INFINITY);
+}
@@ -0,0 +1,241 @@
+use randomx::helpers::{ROUNDING_MODE_NEAREST, ROUNDING_MODE_UP};
+use randomx::parameters::{RANDOMX_SCRATCHPAD_L1, RANDOMX_SCRATCHPAD_L3};
+use randomx::vm::{interpreter, EncodedInstruction, VMEnvironment};
+
+// First opcode of each instruction, following the frequencies defined in the
+// parameters
+const IADD_RS: u8 = 0;
+const IADD_M: u8 = 16;
+const ISUB_R: u8 = 23;
+const IMUL_R: u8 = 46;
+const IMULH_R: u8 = 66;
+const ISMULH_R: u8 = 71;
+const IMUL_RCP: u8 = 76;
+const INEG_R: u8 = 84;
+const IXOR_R: u8 = 86;
+const IROR_R: u8 = 106;
+const IROL_R: u8 = 114;
+const ISWAP_R: u8 = 116;
+const FSWAP_R: u8 = 120;
+const FADD_R: u8 = 124;
+const FSUB_M: u8 = 161;
+const FSCAL_R: u8 = 166;
+const FMUL_R: u8 = 172;
+const FDIV_M: u8 = 204;
+const FSQRT_R: u8 = 208;
+const CBRANCH: u8 = 214;
+const CFROUND: u8 = 239;
+const ISTORE: u8 = 240;
+
+fn encode(opcode: u8, dst: u8, src: u8, mod_: u8, imm: u32) -> EncodedInstruction {
+    opcode as u64
+        | (dst as u64) << 8
+        | (src as u64) << 16
+        | (mod_ as u64) << 24
+        | (imm as u64) << 32
+}
+
+fn execute(env: &mut VMEnvironment, program: &[EncodedInstruction]) {
+    env.set_program(program);
+    interpreter(env);
+}
+
+#[test]
+fn test_interpreter_iadd_rs() {
+    let mut env = VMEnvironment::new().unwrap();
+    env.registers.r[0] = 0x8000000000000000;
+    env.registers.r[1] = 0x1000000000000000;
+    // mod.shift = 3
+    execute(&mut env, &[encode(IADD_RS, 0, 1, 3 << 2, 0)]);
+    assert_eq!(env.registers.r[0], 0);
+
+    // r5 uses the immediate as displacement
+    env.registers.r[5] = 10;
+    env.registers.r[1] = 1;
+    execute(&mut env, &[encode(IADD_RS, 5, 1, 0, 0xFFFFFFFF)]);
+    assert_eq!(env.registers.r[5], 10);
+}
+
+#[test]
+fn test_interpreter_iadd_m_addressing() {
+    let mut env = VMEnvironment::new().unwrap();
+    env.scratchpad[0x18..0x20].copy_from_slice(&7u64.to_le_bytes());
+    env.scratchpad[RANDOMX_SCRATCHPAD_L1 as usize..RANDOMX_SCRATCHPAD_L1 as usize + 8]
+        .copy_from_slice(&11u64.to_le_bytes());
+    env.scratchpad[RANDOMX_SCRATCHPAD_L3 as usize - 8..].copy_from_slice(&13u64.to_le_bytes());
+
+    // mod.mem != 0: L1 address, wrapping in the first 16 KiB
+    env.registers.r[1] = RANDOMX_SCRATCHPAD_L1 + 0x10;
+    execute(&mut env, &[encode(IADD_M, 0, 1, 1, 8)]);
+    assert_eq!(env.registers.r[0], 7);
+
+    // mod.mem == 0: L2 address
+    env.registers.r[1] = RANDOMX_SCRATCHPAD_L1 - 8;
+    execute(&mut env, &[encode(IADD_M, 0, 1, 0, 8)]);
+    assert_eq!(env.registers.r[0], 18);
+
+    // src == dst: the immediate is an L3 address
+    execute(&mut env, &[encode(IADD_M, 0, 0, 1, 0xFFFFFFF8)]);
+    assert_eq!(env.registers.r[0], 31);
+}
+
+#[test]
+fn test_interpreter_integer_register_operations() {
+    let mut env = VMEnvironment::new().unwrap();
+
+    // src == dst: the sign-extended immediate is used
+    env.registers.r[0] = 5;
+    execute(&mut env, &[encode(ISUB_R, 0, 0, 0, 0xFFFFFFFF)]);
+    assert_eq!(env.registers.r[0], 6);
+
+    env.registers.r[1] = 3;
+    execute(&mut env, &[encode(IMUL_R, 0, 1, 0, 0)]);
+    assert_eq!(env.registers.r[0], 18);
+
+    env.registers.r[0] = u64::MAX;
+    env.registers.r[1] = 2;
+    execute(&mut env, &[encode(IMULH_R, 0, 1, 0, 0)]);
+    assert_eq!(env.registers.r[0], 1);
+
+    env.registers.r[0] = u64::MAX;
+    execute(&mut env, &[encode(ISMULH_R, 0, 1, 0, 0)]);
+    assert_eq!(env.registers.r[0], u64::MAX);
+
+    env.registers.r[0] = 1;
+    execute(&mut env, &[encode(INEG_R, 0, 0, 0, 0)]);
+    assert_eq!(env.registers.r[0], u64::MAX);
+
+    env.registers.r[1] = 0xFF;
+    execute(&mut env, &[encode(IXOR_R, 0, 1, 0, 0)]);
+    assert_eq!(env.registers.r[0], 0xFFFFFFFFFFFFFF00);
+
+    // Only the 6 lower bits of the source are used for the rotation
+    env.registers.r[0] = 1;
+    env.registers.r[1] = 64 + 4;
+    execute(&mut env, &[encode(IROR_R, 0, 1, 0, 0)]);
+    assert_eq!(env.registers.r[0], 0x1000000000000000);
+    execute(&mut env, &[encode(IROL_R, 0, 0, 0, 8)]);
+    assert_eq!(env.registers.r[0], 0x10);
+
+    env.registers.r[0] = 1;
+    env.registers.r[1] = 2;
+    execute(&mut env, &[encode(ISWAP_R, 0, 1, 0, 0)]);
+    assert_eq!(env.registers.r[0..2], [2, 1]);
+}
+
+#[test]
+fn test_interpreter_imul_rcp() {
+    let mut env = VMEnvironment::new().unwrap();
+    env.registers.r[0] = 3;
+    execute(&mut env, &[encode(IMUL_RCP, 0, 0, 0, 3)]);
+    // The reciprocal of 3 is 0xAAAAAAAAAAAAAAAA
+    assert_eq!(env.registers.r[0], 0xFFFFFFFFFFFFFFFE);
+
+    // Zero and powers of two are ignored
+    execute(
+        &mut env,
+        &[encode(IMUL_RCP, 0, 0, 0, 0), encode(IMUL_RCP, 0, 0, 0, 8)],
+    );
+    assert_eq!(env.registers.r[0], 0xFFFFFFFFFFFFFFFE);
+}
+
+#[test]
+fn test_interpreter_float_operations() {
+    let mut env = VMEnvironment::new().unwrap();
+    env.registers.f[0] = [1.0, 2.0];
+    env.registers.a[1] = [0.5f64.to_bits(), 0.25f64.to_bits()];
+    execute(&mut env, &[encode(FADD_R, 0, 1, 0, 0)]);
+    assert_eq!(env.registers.f[0], [1.5, 2.25]);
+
+    execute(&mut env, &[encode(FSWAP_R, 0, 0, 0, 0)]);
+    assert_eq!(env.registers.f[0], [2.25, 1.5]);
+    env.registers.e[1] = [3.0, 4.0];
+    execute(&mut env, &[encode(FSWAP_R, 5, 0, 0, 0)]);
+    assert_eq!(env.registers.e[1], [4.0, 3.0]);
+
+    // The low half is loaded from the first 4 bytes
+    env.scratchpad[0x40..0x44].copy_from_slice(&3i32.to_le_bytes());
+    env.scratchpad[0x44..0x48].copy_from_slice(&(-5i32).to_le_bytes());
+    env.registers.f[2] = [1.0, 1.0];
+    env.registers.r[3] = 0x40;
+    execute(&mut env, &[encode(FSUB_M, 2, 3, 1, 0)]);
+    assert_eq!(env.registers.f[2], [-2.0, 6.0]);
+
+    execute(&mut env, &[encode(FSCAL_R, 2, 0, 0, 0)]);
+    assert_eq!(
+        env.registers.f[2][0].to_bits(),
+        (-2.0f64).to_bits() ^ 0x80F0000000000000
+    );
+    assert_eq!(
+        env.registers.f[2][1].to_bits(),
+        6.0f64.to_bits() ^ 0x80F0000000000000
+    );
+
+    env.registers.e[0] = [3.0, 0.5];
+    env.registers.a[0] = [2.0f64.to_bits(), 4.0f64.to_bits()];
+    execute(&mut env, &[encode(FMUL_R, 0, 0, 0, 0)]);
+    assert_eq!(env.registers.e[0], [6.0, 2.0]);
+
+    // The exponent of the divisor is replaced using emask
+    env.configuration.emask = [0x4000000000000000; 2];
+    env.scratchpad[0x40..0x44].copy_from_slice(&2i32.to_le_bytes());
+    env.scratchpad[0x44..0x48].copy_from_slice(&2i32.to_le_bytes());
+    execute(&mut env, &[encode(FDIV_M, 0, 3, 1, 0)]);
+    assert_eq!(env.registers.e[0], [3.0, 1.0]);
+
+    env.registers.e[3] = [4.0, 9.0];
+    execute(&mut env, &[encode(FSQRT_R, 3, 0, 0, 0)]);
+    assert_eq!(env.registers.e[3], [2.0, 3.0]);
+}
+
+#[test]
+fn test_interpreter_cfround() {
+    let mut env = VMEnvironment::new().unwrap();
+    assert_eq!(env.fprc, ROUNDING_MODE_NEAREST);
+    env.registers.r[1] = (ROUNDING_MODE_UP as u64) << 5;
+    // Only the 6 lower bits of the immediate are used
+    execute(&mut env, &[encode(CFROUND, 0, 1, 0, 64 + 5)]);
+    assert_eq!(env.fprc, ROUNDING_MODE_UP);
+
+    // The rounding mode is used by the following instructions
+    env.registers.f[0] = [1.0, 1.0];
+    env.registers.a[0] = [(f64::EPSILON / 2.0).to_bits(), 0];
+    execute(&mut env, &[encode(FADD_R, 0, 0, 0, 0)]);
+    assert_eq!(env.registers.f[0], [1.0 + f64::EPSILON, 1.0]);
+}
+
+#[test]
+fn test_interpreter_cbranch() {
+    let mut env = VMEnvironment::new().unwrap();
+    env.registers.r[0] = 0xFF00;
+    env.registers.r[2] = 1;
+    // With mod.cond = 0, the branch is taken when the bits 8-15 of r0 are
+    // zero after adding 1 << 8. It jumps after the last instruction that
+    // modified r0, here the beginning of the program.
+    execute(
+        &mut env,
+        &[encode(IADD_RS, 1, 2, 0, 0), encode(CBRANCH, 0, 0, 0, 0)],
+    );
+    assert_eq!(env.registers.r[0], 0x10100);
+    assert_eq!(env.registers.r[1], 2);
+}
+
+#[test]
+fn test_interpreter_istore() {
+    let mut env = VMEnvironment::new().unwrap();
+    env.registers.r[0] = 0x100;
+    env.registers.r[1] = 0x0123456789ABCDEF;
+    execute(&mut env, &[encode(ISTORE, 0, 1, 1, 8)]);
+    assert_eq!(
+        env.scratchpad[0x108..0x110],
+        0x0123456789ABCDEFu64.to_le_bytes()
+    );
+
+    // mod.cond >= 14: L3 address
+    env.registers.r[0] = RANDOMX_SCRATCHPAD_L3 - 8;
+    execute(&mut env, &[encode(ISTORE, 0, 1, 0xE1, 0)]);
+    assert_eq!(
+        env.scratchpad[RANDOMX_SCRATCHPAD_L3 as usize - 8..],
+        0x0123456789ABCDEFu64.to_le_bytes()
+    );
+}
@@ -0,0 +1,300 @@
+#![cfg(feature = "jit")]
+
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+use std::sync::OnceLock;
+
+use blake2::{Blake2b512, Digest};
+use randomx::cache::Cache;
+use randomx::dataset::{init_items, Dataset};
+use randomx::flags::RandomXFlags;
+use randomx::jit::{CodeBuffer, JitCompiler};
+use randomx::parameters::RANDOMX_DATASET_ITEM_COUNT;
+use randomx::vm::{DatasetMemory, VMEnvironment, Vm};
+use randomx::Error;
+
+mod common;
+use common::*;
+
+/// Number of iterations of the programs compared with the interpreter. The
+/// loop is the same for all the iterations, a few of them are enough.
+const ITERATIONS: u32 = 8;
+
+fn seed(i: u64) -> [u8; 64] {
+    Blake2b512::digest(i.to_le_bytes()).into()
+}
+
+/// A Dataset shared by the fast mode tests. The items do not need to be
+/// valid, only to be different from each other.
+fn synthetic_dataset() -> &'static Dataset {
+    static DATASET: OnceLock<Dataset> = OnceLock::new();
+    DATASET.get_or_init(|| {
+        let mut dataset = Dataset::new().unwrap();
+        for (i, word) in dataset.memory.chunks_exact_mut(8).enumerate() {
+            word.copy_from_slice(&(i as u64).wrapping_mul(0x9E3779B97F4A7C15).to_le_bytes());
+        }
+        dataset.set_initialized();
+        dataset
+    })
+}
+
+/// Run `count` random programs with the interpreter and with the JIT
+/// compiler and check that the VMs end up in the same state
+fn compare_with_interpreter(memory: DatasetMemory, count: u64) {
+    let mut interpreter_env = VMEnvironment::new().unwrap();
+    let mut jit_env = VMEnvironment::new().unwrap();
+    jit_env.jit = Some(JitCompiler::new(false).unwrap());
+    let mut scratchpad_seed = seed(u64::MAX);
+    interpreter_env.init_scratchpad(&mut scratchpad_seed);
+    let scratchpad = interpreter_env.scratchpad.to_vec();
+
+    for i in 0..count {
+        for env in [&mut interpreter_env, &mut jit_env] {
+            env.scratchpad.copy_from_slice(&scratchpad);
+            env.load_program(seed(i));
+            env.ic = ITERATIONS;
+            env.fprc = (i % 4) as u32;
+            env.run(memory);
+        }
+        assert_eq!(
+            interpreter_env.register_file(),
+            jit_env.register_file(),
+            "program {}",
+            i
+        );
+        assert_eq!(interpreter_env.fprc, jit_env.fprc, "program {}", i);
+        assert_eq!(interpreter_env.ma, jit_env.ma, "program {}", i);
+        assert_eq!(interpreter_env.mx, jit_env.mx, "program {}", i);
+        assert_eq!(interpreter_env.ic, 0);
+        assert_eq!(jit_env.ic, 0);
+        assert!(
+            interpreter_env.scratchpad[..] == jit_env.scratchpad[..],
+            "program {}",
+            i
+        );
+    }
+}
+
+#[test]
+fn test_jit_light_mode_same_as_interpreter() {
+    compare_with_interpreter(DatasetMemory::Light(cache_000()), 2000);
+}
+
+#[test]
+fn test_jit_fast_mode_same_as_interpreter() {
+    compare_with_interpreter(DatasetMemory::Fast(synthetic_dataset()), 1000);
+}
+
+#[test]
+fn test_jit_hash_test_vectors() {
+    let hard_aes = RandomXFlags::HARD_AES.intersection(randomx::flags::get_flags());
+    for (key, input, exp_hash) in HASH_VECTORS {
+        let cache = if key == KEY_000 {
+            cache_000()
+        } else {
+            cache_001()
+        };
+        let mut vm = Vm::new(RandomXFlags::JIT | hard_aes, Some(cache), None).unwrap();
+        assert_eq!(vm.calculate_hash(input), exp_hash);
+    }
+}
+
+#[test]
+fn test_jit_secure_mode() {
+    let mut vm = Vm::new(
+        RandomXFlags::JIT | RandomXFlags::SECURE,
+        Some(cache_000()),
+        None,
+    )
+    .unwrap();
+    assert_eq!(vm.calculate_hash(THIS_IS_A_TEST), HASH_000_THIS_IS_A_TEST);
+    assert_eq!(vm.calculate_hash(LOREM_IPSUM), HASH_000_LOREM_IPSUM);
+}
+
+/// `mov eax, imm32; ret`
+fn return_code(value: u32) -> Vec<u8> {
+    let mut code = vec![0xB8];
+    code.extend_from_slice(&value.to_le_bytes());
+    code.push(0xC3);
+    code
+}
+
+/// Call the code at the beginning of `buffer`, which must return a u32
+fn call(buffer: &CodeBuffer) -> u32 {
+    // SAFETY: the buffer contains code written by `return_code`
+    let code: extern "sysv64" fn() -> u32 = unsafe { std::mem::transmute(buffer.as_ptr()) };
+    code()
+}
+
+#[test]
+fn test_code_buffer() {
+    for secure in [false, true] {
+        let mut buffer = CodeBuffer::new(4096, secure).unwrap();
+        assert_eq!(buffer.is_secure(), secure);
+        buffer.write(&return_code(1)).unwrap();
+        assert_eq!(call(&buffer), 1);
+        buffer.write(&return_code(2)).unwrap();
+        assert_eq!(call(&buffer), 2);
+    }
+
+    // The code must fit the buffer
+    let mut buffer = CodeBuffer::new(4096, true).unwrap();
+    assert_eq!(
+        buffer.write(&[0xC3; 4097]),
+        Err(Error::AllocationFailed { size: 4097 })
+    );
+    buffer.write(&return_code(4)).unwrap();
+    assert_eq!(call(&buffer), 4);
+
+    // By default, the buffer can be modified while it is executable
+    let mut buffer = CodeBuffer::new(4096, false).unwrap();
+    buffer.write(&return_code(1)).unwrap();
+    // SAFETY: the buffer is mapped readable, writable and executable
+    unsafe { (buffer.as_ptr() as *mut u8).add(1).write_volatile(3) };
+    assert_eq!(call(&buffer), 3);
+}
+
+/// Environment variable telling the test run in a child process to write to
+/// the executable buffer
+const CHILD_ENV: &str = "RANDOMX_TEST_SECURE_WRITE";
+
+#[test]
+fn test_code_buffer_secure_write_faults() {
+    if std::env::var_os(CHILD_ENV).is_some() {
+        let mut buffer = CodeBuffer::new(4096, true).unwrap();
+        buffer.write(&return_code(1)).unwrap();
+        assert_eq!(call(&buffer), 1);
+        // SAFETY: none, the buffer is not writable and the write is expected
+        // to kill the process
+        unsafe { (buffer.as_ptr() as *mut u8).add(1).write_volatile(3) };
+        // Not reached: exit normally so that the parent reports the failure
+        std::process::exit(0);
+    }
+
+    // Run only this test in a child process
+    let status = Command::new(std::env::current_exe().unwrap())
+        .args(["test_code_buffer_secure_write_faults", "--exact"])
+        .env(CHILD_ENV, "1")
+        .status()
+        .unwrap();
+    assert!(
+        matches!(status.signal(), Some(libc::SIGSEGV) | Some(libc::SIGBUS)),
+        "the write did not fault: {}",
+        status
+    );
+}
+
+#[test]
+fn test_jit_fast_mode_hashes_same_as_interpreter() {
+    // In fast mode the items are read from memory, so hundreds of hashes
+    // are quick
+    let dataset = synthetic_dataset();
+    let mut interpreter_vm = Vm::new(RandomXFlags::FULL_MEM, None, Some(dataset)).unwrap();
+    let mut jit_vm = Vm::new(
+        RandomXFlags::FULL_MEM | RandomXFlags::JIT,
+        None,
+        Some(dataset),
+    )
+    .unwrap();
+    for i in 0..200u64 {
+        let input = seed(i);
+        assert_eq!(
+            interpreter_vm.calculate_hash(&input),
+            jit_vm.calculate_hash(&input),
+            "input {}",
+            i
+        );
+    }
+}
+
+/// Number of inputs of [INTERPRETER_HASHES_DIGEST]
+const HASH_COUNT: u64 = 2000;
+
+/// [hashes_digest] of [HASH_COUNT] inputs over [synthetic_dataset],
+/// computed with the interpreter by
+/// test_interpreter_fast_mode_hashes_digest
+const INTERPRETER_HASHES_DIGEST: [u8; 64] = [
+    0x41, 0x60, 0x12, 0xfd, 0xa5, 0x41, 0xe5, 0x6c, 0xc8, 0x64, 0x24, 0xe1, 0x63, 0x13, 0x4d, 0x41,
+    0x36, 0x5c, 0x56, 0x6b, 0x58, 0x17, 0x9e, 0x27, 0x72, 0xbf, 0x8e, 0xd2, 0x5e, 0x00, 0x2a, 0xdd,
+    0xdd, 0xe1, 0x3d, 0x66, 0x27, 0x16, 0x25, 0xf8, 0x59, 0xdc, 0x69, 0xbc, 0x25, 0x16, 0x34, 0x89,
+    0x64, 0x24, 0xe1, 0x1c, 0x66, 0x67, 0xda, 0x8c, 0x4c, 0x7e, 0xb6, 0x45, 0x09, 0x23, 0xe7, 0x46,
+];
+
+/// Blake2b-512 of the hashes of the inputs `seed(0..count)`, computed by
+/// `vm`
+fn hashes_digest(vm: &mut Vm, count: u64) -> [u8; 64] {
+    let mut hasher = Blake2b512::new();
+    for i in 0..count {
+        hasher.update(vm.calculate_hash(&seed(i)));
+    }
+    hasher.finalize().into()
+}
+
+#[test]
+fn test_jit_fast_mode_thousands_of_hashes() {
+    let mut jit_vm = Vm::new(
+        RandomXFlags::FULL_MEM | RandomXFlags::JIT,
+        None,
+        Some(synthetic_dataset()),
+    )
+    .unwrap();
+    assert_eq!(
+        hashes_digest(&mut jit_vm, HASH_COUNT),
+        INTERPRETER_HASHES_DIGEST
+    );
+}
+
+#[test]
+#[ignore = "computes thousands of hashes with the interpreter, which takes several minutes"]
+fn test_interpreter_fast_mode_hashes_digest() {
+    let mut interpreter_vm =
+        Vm::new(RandomXFlags::FULL_MEM, None, Some(synthetic_dataset())).unwrap();
+    assert_eq!(
+        hashes_digest(&mut interpreter_vm, HASH_COUNT),
+        INTERPRETER_HASHES_DIGEST
+    );
+}
+
+#[test]
+#[ignore = "computes thousands of hashes in light mode, which takes about an hour"]
+fn test_jit_hashes_same_as_interpreter() {
+    let cache = cache_000();
+    let mut interpreter_vm = Vm::new(RandomXFlags::DEFAULT, Some(cache), None).unwrap();
+    let mut jit_vm = Vm::new(RandomXFlags::JIT, Some(cache), None).unwrap();
+    for i in 0..2000u64 {
+        let input = seed(i);
+        assert_eq!(
+            interpreter_vm.calculate_hash(&input),
+            jit_vm.calculate_hash(&input),
+            "input {}",
+            i
+        );
+    }
+}
+
+#[test]
+fn test_superscalar_jit_same_as_interpreter() {
+    let mut cache = Cache::new(KEY_000).unwrap();
+    assert!(cache.jit.is_some());
+
+    let mut items = vec![0u8; 64 * 4096];
+    for start_item in [0, 10000000, 20000000, RANDOMX_DATASET_ITEM_COUNT - 4096] {
+        init_items(&cache, start_item, &mut items);
+        for (i, item) in items.chunks_exact(64).enumerate() {
+            assert_eq!(item, cache.dataset_item(start_item + i as u64));
+        }
+    }
+
+    let mut item = [0u8; 64];
+    for (item_number, exp_r0) in DATASET_ITEMS_R0_000 {
+        init_items(&cache, item_number, &mut item);
+        assert_eq!(u64::from_le_bytes(item[0..8].try_into().unwrap()), exp_r0);
+    }
+
+    // Without the compiled programs, the items are computed by the
+    // interpreter
+    let jit_items = items.clone();
+    cache.jit = None;
+    init_items(&cache, RANDOMX_DATASET_ITEM_COUNT - 4096, &mut items);
+    assert_eq!(items, jit_items);
+}
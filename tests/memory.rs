@@ -0,0 +1,59 @@
+use randomx::memory::{Memory, PageSize};
+
+/// Number of free 2 MiB huge pages reserved by the system
+fn free_huge_pages() -> usize {
+    std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| {
+            meminfo
+                .lines()
+                .find_map(|line| line.strip_prefix("HugePages_Free:"))
+                .and_then(|free| free.trim().parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+/// Check that the memory is zeroed, aligned and writable
+fn check_memory(memory: &mut Memory<u64>, len: usize) {
+    assert_eq!(memory.len(), len);
+    assert_eq!(memory.as_ptr() as usize % 64, 0);
+    assert!(memory.iter().all(|&word| word == 0));
+    for (i, word) in memory.iter_mut().enumerate() {
+        *word = i as u64;
+    }
+    assert!(memory.iter().enumerate().all(|(i, &word)| word == i as u64));
+}
+
+#[test]
+fn test_memory_normal_pages() {
+    for len in [0, 1, 1000, 1 << 20] {
+        let mut memory = Memory::new(len, PageSize::Normal).unwrap();
+        assert_eq!(memory.page_size(), PageSize::Normal);
+        check_memory(&mut memory, len);
+    }
+}
+
+#[test]
+fn test_memory_large_pages() {
+    // 2 MiB huge pages are only obtained if the system reserved them
+    let len = 1 << 20;
+    let mut memory = Memory::new(len, PageSize::Huge2M).unwrap();
+    check_memory(&mut memory, len);
+    match memory.page_size() {
+        PageSize::Huge2M | PageSize::Transparent => {
+            assert_eq!(memory.as_ptr() as usize % (2 << 20), 0)
+        }
+        PageSize::Normal => assert!(!cfg!(target_os = "linux") || free_huge_pages() < 4),
+        PageSize::Huge1G => panic!("larger pages than requested"),
+    }
+    if cfg!(target_os = "linux") && free_huge_pages() >= 4 {
+        assert_eq!(memory.page_size(), PageSize::Huge2M);
+    }
+
+    // Too small for 1 GiB pages
+    let memory = Memory::<u8>::new(len, PageSize::Huge1G).unwrap();
+    assert_ne!(memory.page_size(), PageSize::Huge1G);
+
+    let memory = Memory::<u8>::new(0, PageSize::Huge2M).unwrap();
+    assert_eq!(memory.page_size(), PageSize::Normal);
+}
@@ -1,9 +1,14 @@
-use std::str::FromStr;
-
 use aes::cipher::{generic_array::GenericArray, BlockDecrypt, KeyInit};
-use aes::Aes128;
+use aes::hazmat::{cipher_round, equiv_inv_cipher_round};
+use aes::{Aes128, Block};
 use blake2::{Blake2b512, Digest};
-use randomx::BlakeGenerator;
+use randomx::aes_round::AesBackend;
+use randomx::flags::RandomXFlags;
+use randomx::parameters::{self, RANDOMX_SCRATCHPAD_L3};
+use randomx::{AesHash1R, BlakeGenerator, Error};
+
+mod common;
+use common::*;
 
 #[test]
 // Test the keys have been generated as described in the specification.
@@ -114,38 +119,265 @@ fn test_regtest_aes_generator_1r() {
     assert_eq!(state0, exp_output.into());
 }
 
-#[test]
-fn test_vectors_aes_generator_1r() {
-    // FIXME: https://github.com/tevador/RandomX/blob/master/src/tests/tests.cpp#L172
-    let mut input: [u8; 64] = [0; 64];
-    let test_vector: [u8; 32] = [
-        0x6c, 0x19, 0x53, 0x6e, 0xb2, 0xde, 0x31, 0xb6, 0xc0, 0x06, 0x5f, 0x7f, 0x11, 0x6e, 0x86,
-        0xf9, 0x60, 0xd8, 0xaf, 0x0c, 0x57, 0x21, 0x0a, 0x65, 0x84, 0xc3, 0x23, 0x7b, 0x9d, 0x06,
-        0x4d, 0xc7,
+fn aes_backends() -> Vec<AesBackend> {
+    let mut backends = vec![AesBackend::Software];
+    if let Ok(hardware) = AesBackend::from_flags(RandomXFlags::HARD_AES) {
+        backends.push(hardware);
+    }
+    backends
+}
+
+/// One `aesenc` round computed with the `aes` crate, independently of the
+/// AES backends of this crate
+fn enc_round(state: &mut [u8; 16], key: &[u8; 16]) {
+    let mut block = Block::from(*state);
+    cipher_round(&mut block, &Block::from(*key));
+    state.copy_from_slice(&block);
+}
+
+/// One `aesdec` round, see [enc_round]
+fn dec_round(state: &mut [u8; 16], key: &[u8; 16]) {
+    let mut block = Block::from(*state);
+    equiv_inv_cipher_round(&mut block, &Block::from(*key));
+    state.copy_from_slice(&block);
+}
+
+/// AesHash1R written after the specification with [enc_round] and
+/// [dec_round]: the columns 0 and 2 are encrypted, the columns 1 and 3 are
+/// decrypted
+fn model_aes_hash1r(input: &[u8]) -> [u8; 64] {
+    let mut states = [
+        parameters::AES_HASH1R_STATE0,
+        parameters::AES_HASH1R_STATE1,
+        parameters::AES_HASH1R_STATE2,
+        parameters::AES_HASH1R_STATE3,
+    ];
+    let keys = input
+        .chunks_exact(64)
+        .flat_map(|block| block.chunks_exact(16).map(|key| key.try_into().unwrap()))
+        .collect::<Vec<[u8; 16]>>();
+    for block_keys in keys.chunks_exact(4).chain([
+        &[parameters::AES_HASH1R_XKEY0; 4][..],
+        &[parameters::AES_HASH1R_XKEY1; 4][..],
+    ]) {
+        for (i, (state, key)) in states.iter_mut().zip(block_keys).enumerate() {
+            if i % 2 == 0 {
+                enc_round(state, key);
+            } else {
+                dec_round(state, key);
+            }
+        }
+    }
+    let mut output = [0u8; 64];
+    for (chunk, state) in output.chunks_exact_mut(16).zip(states.iter()) {
+        chunk.copy_from_slice(state);
+    }
+    output
+}
+
+/// The AES generators written after the specification with [enc_round]
+/// and [dec_round]: `round_keys` lists the keys of the four columns for
+/// each round. The columns 0 and 2 are decrypted, the columns 1 and 3 are
+/// encrypted.
+fn model_aes_generator(input: [u8; 64], round_keys: &[[[u8; 16]; 4]]) -> [u8; 64] {
+    let mut output = input;
+    for keys in round_keys {
+        for (i, (column, key)) in output.chunks_exact_mut(16).zip(keys).enumerate() {
+            let state: &mut [u8; 16] = column.try_into().unwrap();
+            if i % 2 == 0 {
+                dec_round(state, key);
+            } else {
+                enc_round(state, key);
+            }
+        }
+    }
+    output
+}
+
+fn model_aes_generator_1r(input: [u8; 64]) -> [u8; 64] {
+    model_aes_generator(
+        input,
+        &[[
+            parameters::AES_GENERATOR_1R_K0,
+            parameters::AES_GENERATOR_1R_K1,
+            parameters::AES_GENERATOR_1R_K2,
+            parameters::AES_GENERATOR_1R_K3,
+        ]],
+    )
+}
+
+fn model_aes_generator_4r(input: [u8; 64]) -> [u8; 64] {
+    let keys = [
+        parameters::AES_GENERATOR_4R_K0,
+        parameters::AES_GENERATOR_4R_K1,
+        parameters::AES_GENERATOR_4R_K2,
+        parameters::AES_GENERATOR_4R_K3,
+        parameters::AES_GENERATOR_4R_K4,
+        parameters::AES_GENERATOR_4R_K5,
+        parameters::AES_GENERATOR_4R_K6,
+        parameters::AES_GENERATOR_4R_K7,
     ];
-    input[0..32].copy_from_slice(&test_vector);
+    let round_keys = [0, 1, 2, 3].map(|r| [keys[r], keys[r], keys[4 + r], keys[4 + r]]);
+    model_aes_generator(input, &round_keys)
+}
 
-    let exp_output: [u8; 32] = [
+#[test]
+fn test_vectors_aes_generator_1r() {
+    // The first 32 bytes are the reference vector. The others were computed
+    // with this crate and are pinned through the reference hashes, see
+    // test_vectors_aes_hash1r.
+    let exp_output: [u8; 64] = [
         0xfa, 0x89, 0x39, 0x7d, 0xd6, 0xca, 0x42, 0x25, 0x13, 0xae, 0xad, 0xba, 0x3f, 0x12, 0x4b,
         0x55, 0x40, 0x32, 0x4c, 0x4a, 0xd4, 0xb6, 0xdb, 0x43, 0x43, 0x94, 0x30, 0x7a, 0x17, 0xc8,
-        0x33, 0xab,
+        0x33, 0xab, 0xa3, 0x30, 0x40, 0x6d, 0x94, 0x2c, 0xc6, 0xcd, 0x1d, 0x2b, 0x92, 0xa6, 0x17,
+        0xb1, 0x72, 0x6c, 0x56, 0xe2, 0x8c, 0x09, 0x1f, 0x52, 0xd9, 0xd2, 0xeb, 0x2f, 0x52, 0x75,
+        0x37, 0xf2, 0x75, 0x2a,
     ];
+    assert_eq!(exp_output[0..32], AES_GENERATOR_1R_OUTPUT_PREFIX);
+    assert_eq!(model_aes_generator_1r(aes_test_input()), exp_output);
+    for backend in aes_backends() {
+        assert_eq!(
+            randomx::aes_generator_1r_with(backend, aes_test_input()),
+            exp_output
+        );
+    }
 
-    let output = randomx::aes_generator_1r(input);
-    println!("{:?}", output);
-    println!("{:?}", exp_output);
+    // Chain the generator to cover states without zero columns
+    let mut input = aes_test_input();
+    for _ in 0..16 {
+        let exp_output = model_aes_generator_1r(input);
+        assert_eq!(randomx::aes_generator_1r(input), exp_output);
+        for backend in aes_backends() {
+            assert_eq!(randomx::aes_generator_1r_with(backend, input), exp_output);
+        }
+        input = exp_output;
+    }
 }
 
 #[test]
 fn test_vectors_aes_generator_4r() {
-    // FIXME
+    // Computed with this crate, not copied from the fillAes4Rx4 vector of
+    // tests.cpp, see test_vectors_aes_hash1r. AesGenerator4R generates the
+    // programs, so the reference hashes pin it as well.
+    let exp_output: [u8; 64] = [
+        0x75, 0x96, 0xe4, 0x22, 0xdb, 0xa5, 0x3f, 0xa5, 0xc1, 0x12, 0x39, 0x11, 0x78, 0x25, 0x68,
+        0x60, 0xb4, 0x12, 0x4e, 0x33, 0xc3, 0xc1, 0xa6, 0x28, 0x5f, 0xa0, 0x51, 0xa3, 0xc0, 0xa7,
+        0x9a, 0xb4, 0xc9, 0xae, 0x13, 0x20, 0x50, 0x6a, 0xb9, 0x32, 0xd5, 0xad, 0x00, 0xe6, 0x14,
+        0x5c, 0xd6, 0x58, 0x55, 0x4d, 0x4c, 0x88, 0x5c, 0xe0, 0x82, 0xb2, 0x30, 0x31, 0xcd, 0x40,
+        0x71, 0x03, 0xe7, 0x24,
+    ];
+    assert_eq!(model_aes_generator_4r(aes_test_input()), exp_output);
+    for backend in aes_backends() {
+        assert_eq!(
+            randomx::aes_generator_4r_with(backend, aes_test_input()),
+            exp_output
+        );
+    }
+
+    let mut input = aes_test_input();
+    for _ in 0..16 {
+        let exp_output = model_aes_generator_4r(input);
+        assert_eq!(randomx::aes_generator_4r(input), exp_output);
+        for backend in aes_backends() {
+            assert_eq!(randomx::aes_generator_4r_with(backend, input), exp_output);
+        }
+        input = exp_output;
+    }
+}
+
+#[test]
+fn test_aes_generator_4r_programs() {
+    // The programs of the hash of "This is a test" with "test key 000" are
+    // the output of the model. The model is checked against the reference
+    // implementation through the hash.
+    let cache = cache_000();
+    let mut vm_env = execute_programs_with(THIS_IS_A_TEST, |vm_env, seed| {
+        // The first two outputs configure the program
+        let mut state = model_aes_generator_4r(model_aes_generator_4r(seed));
+        for words in vm_env.program_buffer.chunks_exact(8) {
+            state = model_aes_generator_4r(state);
+            let exp_words: Vec<u64> = state
+                .chunks_exact(8)
+                .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+                .collect();
+            assert_eq!(words, exp_words);
+        }
+        vm_env.run(cache);
+    });
+    assert_eq!(vm_env.finalize_hash(), HASH_000_THIS_IS_A_TEST);
+}
+
+#[test]
+fn test_vectors_aes_hash1r() {
+    // 256 bytes of input generated with AesGenerator1R from the test input
+    let mut input = [0u8; 256];
+    let mut state = aes_test_input();
+    for chunk in input.chunks_exact_mut(64) {
+        state = randomx::aes_generator_1r(state);
+        chunk.copy_from_slice(&state);
+    }
+    let exp_hash = model_aes_hash1r(&input);
+
+    assert_eq!(randomx::aes_hash1r(&input), Ok(exp_hash));
+    for backend in aes_backends() {
+        let mut hasher = AesHash1R::with_backend(backend);
+        hasher.update(&input);
+        assert_eq!(hasher.finalize(), Ok(exp_hash));
+    }
+
+    // The reference test hashes the scratchpad filled by fillAes1Rx4 from
+    // the test input. The expected value was computed with this crate, not
+    // copied from tests.cpp: it is pinned through the reference hashes,
+    // which this implementation reproduces with every backend.
+    let mut scratchpad = vec![0u8; RANDOMX_SCRATCHPAD_L3 as usize];
+    let mut state = aes_test_input();
+    for chunk in scratchpad.chunks_exact_mut(64) {
+        state = randomx::aes_generator_1r(state);
+        chunk.copy_from_slice(&state);
+    }
+    let exp_hash: [u8; 64] = [
+        0x26, 0x79, 0x77, 0xec, 0xcb, 0xfd, 0xee, 0xf2, 0x9b, 0x17, 0x90, 0x27, 0xbf, 0x1e, 0xeb,
+        0x39, 0xb0, 0x0f, 0x5c, 0x70, 0x6a, 0x83, 0x3b, 0xd4, 0xa4, 0x7c, 0x92, 0x3d, 0x54, 0xfb,
+        0x44, 0x38, 0x84, 0xda, 0xbb, 0x6d, 0x86, 0x55, 0x80, 0xc5, 0xc6, 0x78, 0x76, 0x52, 0x19,
+        0x69, 0x1d, 0x0e, 0xb1, 0xd3, 0xdb, 0x99, 0x68, 0xa8, 0xdb, 0x22, 0xbc, 0xa0, 0x29, 0x6e,
+        0xfe, 0x0e, 0x90, 0x39,
+    ];
+    assert_eq!(model_aes_hash1r(&scratchpad), exp_hash);
+    for backend in aes_backends() {
+        assert_eq!(randomx::aes_hash1r_with(backend, &scratchpad), Ok(exp_hash));
+    }
+}
+
+#[test]
+fn test_aes_hash1r_scratchpad_fingerprint() {
+    // The fingerprint of the scratchpad at the end of the hash of "This is
+    // a test" with "test key 000". The model is checked against the
+    // reference implementation through the hash.
+    let cache = cache_000();
+    let mut vm_env = execute_programs_with(THIS_IS_A_TEST, |vm_env, _| vm_env.run(cache));
+
+    // Computed with this crate, see test_vectors_aes_hash1r
+    let exp_fingerprint: [u8; 64] = [
+        0xdc, 0x00, 0xb5, 0x0b, 0xf0, 0xdb, 0x2b, 0x45, 0x13, 0xc3, 0xfd, 0x8d, 0xe3, 0xce, 0x4d,
+        0xd1, 0xe3, 0xb2, 0x67, 0x6e, 0x06, 0x5a, 0xe7, 0x3b, 0xa7, 0x45, 0xc7, 0x80, 0xea, 0xf2,
+        0x3a, 0x86, 0x04, 0x9d, 0x92, 0x54, 0x9a, 0x85, 0x24, 0xdc, 0x88, 0x3d, 0x07, 0x94, 0x69,
+        0x8c, 0xff, 0x94, 0x34, 0xef, 0x85, 0xf2, 0x97, 0x35, 0x7f, 0x4b, 0x9c, 0xa5, 0x7f, 0x56,
+        0x19, 0xaa, 0x25, 0xe7,
+    ];
+    assert_eq!(model_aes_hash1r(&vm_env.scratchpad), exp_fingerprint);
+    assert_eq!(randomx::aes_hash1r(&vm_env.scratchpad), Ok(exp_fingerprint));
+    for backend in aes_backends() {
+        assert_eq!(
+            randomx::aes_hash1r_with(backend, &vm_env.scratchpad),
+            Ok(exp_fingerprint)
+        );
+    }
+    assert_eq!(vm_env.finalize_hash(), HASH_000_THIS_IS_A_TEST);
 }
 
 #[test]
 fn test_blake2_generator_init_with_nonce() {
-    // Test generated from reference implementation, commit 89aba80
-    let seed: String = String::from_str("test key 000").unwrap();
-    let seed_u8: Vec<u8> = seed.into_bytes();
+    let seed_u8 = KEY_000.to_vec();
     {
         let blake2_generator = BlakeGenerator::from_seed(seed_u8.clone(), 32);
         assert_eq!(blake2_generator.data[60], 32);
@@ -172,11 +404,87 @@ fn test_blake2_generator_init_with_nonce() {
 
 #[test]
 fn test_blake2_generator_get_byte() {
-    // Test generated from reference implementation, commit 89aba80
-    let seed: String = String::from_str("test key 000").unwrap();
-    let seed_u8: Vec<u8> = seed.into_bytes();
+    let seed_u8 = KEY_000.to_vec();
     let mut blake2_generator = BlakeGenerator::from_seed(seed_u8, 0);
     let res = blake2_generator.get_byte();
     let exp_res: u8 = 216;
     assert_eq!(exp_res, res);
 }
+
+#[test]
+fn test_aes_hash1r_streaming() {
+    // 1 MiB of input generated with AesGenerator1R
+    let mut input = vec![0u8; 1 << 20];
+    let mut state = [0x5a; 64];
+    for chunk in input.chunks_exact_mut(64) {
+        state = randomx::aes_generator_1r(state);
+        chunk.copy_from_slice(&state);
+    }
+    let exp_hash = randomx::aes_hash1r(&input).unwrap();
+
+    // The input can be split anywhere
+    for split in [0, 1, 63, 64, 65, 1000, 1 << 19] {
+        let mut hasher = AesHash1R::new();
+        let (head, tail) = input.split_at(split);
+        hasher.update(head);
+        for part in tail.chunks(4097) {
+            hasher.update(part);
+        }
+        assert_eq!(hasher.finalize(), Ok(exp_hash));
+    }
+
+    // All the blocks are used
+    input[(1 << 20) - 1] ^= 1;
+    assert_ne!(randomx::aes_hash1r(&input).unwrap(), exp_hash);
+}
+
+#[test]
+fn test_aes_hash1r_incomplete_block() {
+    assert_eq!(
+        randomx::aes_hash1r(&[0; 96]),
+        Err(Error::MisalignedInput { length: 96 })
+    );
+    let mut hasher = AesHash1R::new();
+    hasher.update(&[0; 64]);
+    hasher.update(&[0; 1]);
+    assert_eq!(
+        hasher.finalize(),
+        Err(Error::MisalignedInput { length: 65 })
+    );
+}
+
+#[test]
+fn test_blake2_generator_seed_truncated() {
+    // Only the first 60 bytes of the seed are used
+    let mut seed = vec![0x42; 60];
+    let mut blake2_generator = BlakeGenerator::from_seed(seed.clone(), 0);
+    seed.extend_from_slice(&[0x43; 40]);
+    let mut blake2_generator_long = BlakeGenerator::from_seed(seed, 0);
+    assert_eq!(blake2_generator.data, blake2_generator_long.data);
+    for _ in 0..100 {
+        assert_eq!(blake2_generator.get_u32(), blake2_generator_long.get_u32());
+    }
+}
+
+#[test]
+fn test_blake2_generator_get_u32() {
+    // The index advances and the bytes are read in little-endian order
+    let mut blake2_generator = BlakeGenerator::from_seed(KEY_000.to_vec(), 0);
+    assert_eq!(blake2_generator.get_byte(), 216);
+    let exp_u32 = u32::from_le_bytes(blake2_generator.data[1..5].try_into().unwrap());
+    assert_eq!(blake2_generator.get_u32(), exp_u32);
+    assert_eq!(exp_u32, 0x62154cec);
+    assert_eq!(blake2_generator.get_u32(), 0x9c7993f5);
+
+    // Less than 4 bytes left: the state is updated first
+    for _ in 0..52 {
+        blake2_generator.get_byte();
+    }
+    let exp_u32 = {
+        let mut hasher = Blake2b512::new();
+        hasher.update(blake2_generator.data);
+        let data = hasher.finalize();
+        u32::from_le_bytes(data[0..4].try_into().unwrap())
+    };
+    assert_eq!(blake2_generator.get_u32(), exp_u32);
+}
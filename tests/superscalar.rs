@@ -0,0 +1,37 @@
+use randomx::parameters::RANDOMX_CACHE_ACCESSES;
+use randomx::superscalar::SuperscalarProgram;
+use randomx::BlakeGenerator;
+
+#[test]
+fn test_superscalar_program_generate_test_key_000() {
+    // Generated from reference implementation, commit 89aba80, by dumping
+    // the programs built in `randomx_init_cache` for the key "test key 000"
+    let exp_sizes = [447, 438, 449, 447, 444, 449, 452, 437];
+    let exp_addr_regs = [4, 5, 4, 3, 4, 0, 5, 0];
+
+    let mut gen = BlakeGenerator::from_seed(b"test key 000".to_vec(), 0);
+    for i in 0..RANDOMX_CACHE_ACCESSES as usize {
+        let program = SuperscalarProgram::generate(&mut gen);
+        assert_eq!(program.size, exp_sizes[i]);
+        assert_eq!(program.addr_reg, exp_addr_regs[i]);
+        assert_eq!(program.instructions().len(), exp_sizes[i] as usize);
+    }
+}
+
+#[test]
+fn test_superscalar_program_first_instructions() {
+    // First instructions of the first program generated for the key "test key
+    // 000". The programs produce the Dataset items of the reference
+    // implementation, see tests/cache.rs.
+    let mut gen = BlakeGenerator::from_seed(b"test key 000".to_vec(), 0);
+    let program = SuperscalarProgram::generate(&mut gen);
+    let exp_instructions = [
+        0x0000000000000303,
+        0x0000000000010403,
+        0x0000000000070603,
+        0x0000002c00070704,
+        0x0000000044010202,
+        0x68e5ea090000000a,
+    ];
+    assert_eq!(program.instructions()[0..6], exp_instructions);
+}
@@ -1,4 +1,19 @@
-use randomx::vm::VMEnvironment;
+use std::mem::{align_of, offset_of, size_of};
+
+use blake2::{Blake2b512, Digest};
+use randomx::dataset::Dataset;
+use randomx::flags::RandomXFlags;
+use randomx::parameters::{
+    RANDOMX_PROGRAM_ITERATIONS, RANDOMX_PROGRAM_SIZE, RANDOMX_SCRATCHPAD_L3,
+};
+use randomx::vm::{
+    decode, DecodedInstruction, Instruction, ProgramConfiguration, RegisterFile, VMEnvironment, Vm,
+    OPCODE_TABLE,
+};
+use randomx::{aes_generator_1r, aes_generator_4r, Error};
+
+mod common;
+use common::*;
 
 #[test]
 pub fn test_vm_environment_from_configuration() {
@@ -21,7 +36,7 @@ pub fn test_vm_environment_from_configuration() {
         13992127780735554655,
         11551971154970399794,
     ];
-    let vm_env: VMEnvironment = VMEnvironment::from_configuration(config);
+    let vm_env: VMEnvironment = VMEnvironment::from_configuration(config).unwrap();
 
     let hexa_exp_a0_lo: [u8; 8] = [0x41, 0x8e, 0x4a, 0x29, 0x7e, 0xbf, 0xc3, 0x04];
     let hexa_exp_a0_hi: [u8; 8] = [0x40, 0x19, 0xc8, 0x56, 0xc2, 0x67, 0x08, 0xa9];
@@ -34,14 +49,14 @@ pub fn test_vm_environment_from_configuration() {
     let hexa_exp_emask0: [u8; 8] = [0x3c, 0x00, 0x00, 0x00, 0x00, 0x1e, 0x14, 0x5f];
     let hexa_exp_emask1: [u8; 8] = [0x3a, 0x00, 0x00, 0x00, 0x00, 0x11, 0xd4, 0x32];
     // a-registers
-    assert_eq!(vm_env.a_registers[0][1].to_be_bytes(), hexa_exp_a0_lo);
-    assert_eq!(vm_env.a_registers[0][0].to_be_bytes(), hexa_exp_a0_hi);
-    assert_eq!(vm_env.a_registers[1][1].to_be_bytes(), hexa_exp_a1_lo);
-    assert_eq!(vm_env.a_registers[1][0].to_be_bytes(), hexa_exp_a1_hi);
-    assert_eq!(vm_env.a_registers[2][1].to_be_bytes(), hexa_exp_a2_lo);
-    assert_eq!(vm_env.a_registers[2][0].to_be_bytes(), hexa_exp_a2_hi);
-    assert_eq!(vm_env.a_registers[3][1].to_be_bytes(), hexa_exp_a3_lo);
-    assert_eq!(vm_env.a_registers[3][0].to_be_bytes(), hexa_exp_a3_hi);
+    assert_eq!(vm_env.registers.a[0][0].to_be_bytes(), hexa_exp_a0_lo);
+    assert_eq!(vm_env.registers.a[0][1].to_be_bytes(), hexa_exp_a0_hi);
+    assert_eq!(vm_env.registers.a[1][0].to_be_bytes(), hexa_exp_a1_lo);
+    assert_eq!(vm_env.registers.a[1][1].to_be_bytes(), hexa_exp_a1_hi);
+    assert_eq!(vm_env.registers.a[2][0].to_be_bytes(), hexa_exp_a2_lo);
+    assert_eq!(vm_env.registers.a[2][1].to_be_bytes(), hexa_exp_a2_hi);
+    assert_eq!(vm_env.registers.a[3][0].to_be_bytes(), hexa_exp_a3_lo);
+    assert_eq!(vm_env.registers.a[3][1].to_be_bytes(), hexa_exp_a3_hi);
 
     // emask0 and emask1
     assert_eq!(vm_env.configuration.emask[0].to_be_bytes(), hexa_exp_emask0);
@@ -52,3 +67,230 @@ pub fn test_vm_environment_from_configuration() {
     // FIXME: ma
     // FIXME: mx
 }
+
+#[test]
+fn test_opcode_table() {
+    // Each instruction has RANDOMX_FREQ_* consecutive opcodes
+    assert_eq!(OPCODE_TABLE[0], Instruction::IADD_RS);
+    assert_eq!(OPCODE_TABLE[15], Instruction::IADD_RS);
+    assert_eq!(OPCODE_TABLE[16], Instruction::IADD_M);
+    assert_eq!(OPCODE_TABLE[76], Instruction::IMUL_RCP);
+    assert_eq!(OPCODE_TABLE[83], Instruction::IMUL_RCP);
+    assert_eq!(OPCODE_TABLE[120], Instruction::FSWAP_R);
+    assert_eq!(OPCODE_TABLE[203], Instruction::FMUL_R);
+    assert_eq!(OPCODE_TABLE[214], Instruction::CBRANCH);
+    assert_eq!(OPCODE_TABLE[239], Instruction::CFROUND);
+    assert_eq!(OPCODE_TABLE[240], Instruction::ISTORE);
+    assert_eq!(OPCODE_TABLE[255], Instruction::ISTORE);
+    // RANDOMX_FREQ_NOP is 0
+    assert!(!OPCODE_TABLE.contains(&Instruction::NOP));
+}
+
+#[test]
+fn test_decode() {
+    // imm32 = 0xFFFFFFF0, mod = 0b1101_0110, src = 13, dst = 10, opcode = 214
+    let decoded = decode(0xFFFFFFF0D60D0AD6);
+    assert_eq!(
+        decoded,
+        DecodedInstruction {
+            instruction: Instruction::CBRANCH,
+            dst: 2,
+            src: 5,
+            mod_mem: 2,
+            mod_shift: 1,
+            mod_cond: 13,
+            imm: 0xFFFFFFFFFFFFFFF0,
+            imm32: 0xFFFFFFF0,
+        }
+    );
+}
+
+#[test]
+fn test_load_program() {
+    let seed: [u8; 64] = Blake2b512::digest(THIS_IS_A_TEST).into();
+    let mut vm_env = VMEnvironment::new().unwrap();
+    vm_env.load_program(seed);
+
+    // The first 128 bytes of the generator output are the configuration,
+    // followed by the instructions
+    let state0 = aes_generator_4r(seed);
+    let state1 = aes_generator_4r(state0);
+    let state2 = aes_generator_4r(state1);
+    let mut config = [0u64; 16];
+    for (i, word) in state0
+        .chunks_exact(8)
+        .chain(state1.chunks_exact(8))
+        .enumerate()
+    {
+        config[i] = u64::from_le_bytes(word.try_into().unwrap());
+    }
+    let exp_vm_env = VMEnvironment::from_configuration(config).unwrap();
+    assert_eq!(vm_env.registers.a, exp_vm_env.registers.a);
+    assert_eq!(vm_env.configuration.emask, exp_vm_env.configuration.emask);
+    assert_eq!(vm_env.ma, exp_vm_env.ma);
+    assert_eq!(vm_env.mx, exp_vm_env.mx);
+    assert_eq!(vm_env.dataset_offset, exp_vm_env.dataset_offset);
+
+    assert_eq!(vm_env.program_buffer.len(), RANDOMX_PROGRAM_SIZE as usize);
+    for (i, word) in state2.chunks_exact(8).enumerate() {
+        assert_eq!(
+            vm_env.program_buffer[i],
+            u64::from_le_bytes(word.try_into().unwrap())
+        );
+    }
+
+    // Loading another program replaces the previous one
+    vm_env.load_program([0; 64]);
+    assert_eq!(vm_env.program_buffer.len(), RANDOMX_PROGRAM_SIZE as usize);
+    assert_ne!(vm_env.registers.a, exp_vm_env.registers.a);
+}
+
+#[test]
+fn test_init_scratchpad() {
+    let input_seed: [u8; 64] = Blake2b512::digest(THIS_IS_A_TEST).into();
+    let mut seed = input_seed;
+    let mut vm_env = VMEnvironment::new().unwrap();
+    vm_env.init_scratchpad(&mut seed);
+
+    assert_eq!(vm_env.scratchpad.len(), RANDOMX_SCRATCHPAD_L3 as usize);
+    assert_eq!(vm_env.scratchpad[0..64], aes_generator_1r(input_seed));
+    assert_eq!(
+        vm_env.scratchpad[64..128],
+        aes_generator_1r(aes_generator_1r(input_seed))
+    );
+    // The final state of the generator is the seed of the first program
+    assert_eq!(
+        vm_env.scratchpad[RANDOMX_SCRATCHPAD_L3 as usize - 64..],
+        seed
+    );
+
+    // The first 32 bytes of the output of fillAes1Rx4 are the beginning of
+    // the scratchpad
+    let mut seed = aes_test_input();
+    vm_env.init_scratchpad(&mut seed);
+    assert_eq!(vm_env.scratchpad[0..32], AES_GENERATOR_1R_OUTPUT_PREFIX);
+
+    // Blake2b-512 of the whole scratchpad. It was computed with this crate,
+    // not taken from the reference implementation, and is pinned through
+    // the reference hashes, which start from this scratchpad.
+    let exp_fingerprint: [u8; 64] = [
+        0xb5, 0x1c, 0xf5, 0xb8, 0x3b, 0x26, 0x55, 0x7f, 0x87, 0xa7, 0x46, 0xdd, 0xf8, 0xfe, 0xe5,
+        0x1e, 0xb2, 0xa2, 0xe0, 0xa0, 0x7d, 0x67, 0x8d, 0x46, 0x38, 0xdd, 0xf0, 0xff, 0xb2, 0x0e,
+        0x4f, 0x6f, 0x90, 0x09, 0x13, 0xed, 0x91, 0x8f, 0x4f, 0xac, 0x11, 0x7c, 0x78, 0xc3, 0x44,
+        0x5b, 0x0e, 0x59, 0x6b, 0x11, 0x59, 0xde, 0x8f, 0x8e, 0x46, 0x82, 0x67, 0x18, 0xf9, 0xea,
+        0x34, 0xe4, 0x97, 0x81,
+    ];
+    assert_eq!(
+        Blake2b512::digest(&vm_env.scratchpad[..])[..],
+        exp_fingerprint
+    );
+}
+
+#[test]
+fn test_run_program() {
+    let cache = cache_000();
+    let mut vm_env = execute_programs_with(THIS_IS_A_TEST, |vm_env, _| {
+        assert_eq!(vm_env.ic, RANDOMX_PROGRAM_ITERATIONS);
+        vm_env.run(cache);
+        assert_eq!(vm_env.ic, 0);
+    });
+    assert_eq!(vm_env.finalize_hash(), HASH_000_THIS_IS_A_TEST);
+}
+
+#[test]
+fn test_register_file_layout() {
+    let mut vm_env = VMEnvironment::new().unwrap();
+    vm_env.registers.r = [1, 2, 3, 4, 5, 6, 7, 8];
+    vm_env.registers.f[0] = [f64::from_bits(9), f64::from_bits(10)];
+    vm_env.registers.e[3] = [f64::from_bits(11), f64::from_bits(12)];
+    vm_env.registers.a[1] = [13, 14];
+
+    // r0-r7, f0-f3, e0-e3, a0-a3, the low half of each register first
+    let register_file = vm_env.register_file();
+    let word = |i: usize| u64::from_le_bytes(register_file[8 * i..8 * i + 8].try_into().unwrap());
+    for i in 0..8 {
+        assert_eq!(word(i), i as u64 + 1);
+    }
+    assert_eq!(word(8), 9);
+    assert_eq!(word(9), 10);
+    assert_eq!(word(22), 11);
+    assert_eq!(word(23), 12);
+    assert_eq!(word(26), 13);
+    assert_eq!(word(27), 14);
+}
+
+#[test]
+fn test_register_file_repr() {
+    // Offsets of the fields of RegisterFile and ProgramConfiguration in the
+    // reference implementation
+    assert_eq!(size_of::<RegisterFile>(), 256);
+    assert_eq!(align_of::<RegisterFile>(), 64);
+    assert_eq!(offset_of!(RegisterFile, r), 0);
+    assert_eq!(offset_of!(RegisterFile, f), 64);
+    assert_eq!(offset_of!(RegisterFile, e), 128);
+    assert_eq!(offset_of!(RegisterFile, a), 192);
+    assert_eq!(align_of::<ProgramConfiguration>(), 64);
+    assert_eq!(offset_of!(ProgramConfiguration, emask), 0);
+    assert_eq!(offset_of!(ProgramConfiguration, read_reg0), 16);
+    assert_eq!(offset_of!(ProgramConfiguration, read_reg1), 20);
+    assert_eq!(offset_of!(ProgramConfiguration, read_reg2), 24);
+    assert_eq!(offset_of!(ProgramConfiguration, read_reg3), 28);
+
+    let mut vm_env = VMEnvironment::new().unwrap();
+    assert_eq!(vm_env.scratchpad.as_ptr() as usize % 64, 0);
+    for (i, r) in vm_env.registers.r.iter_mut().enumerate() {
+        *r = 0x0101010101010101 * i as u64;
+    }
+    vm_env.registers.f[1] = [1.0, 2.0];
+    vm_env.registers.a[3] = [3, 4];
+    // The serialized register file is the memory of the structure
+    #[cfg(target_endian = "little")]
+    {
+        // SAFETY: RegisterFile is 256 bytes of plain integers and floats
+        let memory: [u8; 256] = unsafe { std::mem::transmute(vm_env.registers) };
+        assert_eq!(vm_env.register_file(), memory);
+    }
+}
+
+#[test]
+fn test_randomx_flags() {
+    let flags = RandomXFlags::FULL_MEM | RandomXFlags::HARD_AES;
+    assert_eq!(flags.bits(), 6);
+    assert!(flags.contains(RandomXFlags::FULL_MEM));
+    assert!(!flags.contains(RandomXFlags::JIT));
+    assert!(!RandomXFlags::ARGON2_AVX2.contains(RandomXFlags::ARGON2));
+    assert!(RandomXFlags::ARGON2.contains(RandomXFlags::ARGON2_SSSE3));
+    assert_eq!(RandomXFlags::default(), RandomXFlags::DEFAULT);
+}
+
+#[test]
+fn test_vm_new_errors() {
+    assert_eq!(
+        Vm::new(RandomXFlags::FULL_MEM, None, None).err(),
+        Some(Error::DatasetNotInitialized)
+    );
+    assert_eq!(
+        Vm::new(RandomXFlags::DEFAULT, None, None).err(),
+        Some(Error::CacheNotInitialized)
+    );
+
+    // The Dataset must be initialized
+    let mut dataset = Dataset::new().unwrap();
+    assert!(!dataset.is_initialized());
+    assert_eq!(
+        Vm::new(RandomXFlags::FULL_MEM, None, Some(&dataset)).err(),
+        Some(Error::DatasetNotInitialized)
+    );
+    dataset.init(cache_000(), 0, 1).unwrap();
+    assert!(dataset.is_initialized());
+    assert!(Vm::new(RandomXFlags::FULL_MEM, None, Some(&dataset)).is_ok());
+
+    let mut dataset = Dataset::new().unwrap();
+    dataset.set_initialized();
+    assert!(Vm::new(RandomXFlags::FULL_MEM, None, Some(&dataset)).is_ok());
+    #[cfg(not(feature = "jit"))]
+    assert_eq!(
+        Vm::new(RandomXFlags::JIT | RandomXFlags::FULL_MEM, None, None).err(),
+        Some(Error::UnsupportedFlag(RandomXFlags::JIT))
+    );
+}